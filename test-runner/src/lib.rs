@@ -8,6 +8,8 @@ fn doc_gen() {
         "../programs/doc-gen/main.tnt".as_ref(),
         Some("../programs/doc-gen/output.md".as_ref()),
         None,
+        None,
+        false,
     );
 }
 
@@ -17,6 +19,8 @@ fn doc_test() {
         "../programs/doc-test/main.tnt".as_ref(),
         Some("../programs/doc-test/output.txt".as_ref()),
         None,
+        None,
+        false,
     );
 }
 
@@ -26,6 +30,8 @@ fn literate() {
         "../programs/literate/main.tnt".as_ref(),
         Some("../programs/literate/output.md".as_ref()),
         None,
+        None,
+        false,
     );
 }
 
@@ -46,46 +52,76 @@ impl Write for SharedSink {
     }
 }
 
-fn do_run(source: &str) -> (String, String) {
+// Runs `f` on a thread with as much stack as the main thread of `tontuna`
+// gets, test threads have too little of it for deep calls in programs.
+fn with_program_stack<T: Send>(f: impl FnOnce() -> T + Send) -> T {
+    std::thread::scope(|scope| {
+        std::thread::Builder::new()
+            .stack_size(8 << 20)
+            .spawn_scoped(scope, f)
+            .unwrap()
+            .join()
+            .unwrap_or_else(|e| std::panic::resume_unwind(e))
+    })
+}
+
+// Returns stdout, stderr and the program source with requested edits applied.
+fn do_run(source: &str, options: &tontuna::EvalOptions) -> (String, String, String) {
     let src = tontuna::Source::new(source.to_owned());
     let ast = match tontuna::parse(source) {
         Ok(ast) => ast,
         Err(e) => return (
             "".to_owned(),
             format!("parse error at line {}: {}", src.span_start_line(e.span), e.message),
+            source.to_owned(),
         ),
     };
     let output = SharedSink {
         result: Default::default(),
     };
-    let stderr = match tontuna::eval(&ast, Box::new(output.clone())) {
-        Ok(()) => "".to_owned(),
-        Err(e) => format!("runtime error at line {}: {}", src.span_start_line(e.span), e.message),
+    let (stderr, edited) = match tontuna::eval_with_options(&ast, Box::new(output.clone()), options) {
+        Ok(edits) => ("".to_owned(), tontuna::apply_edits(source, &edits)),
+        Err(e) => (
+            format!("runtime error at line {}: {}", src.span_start_line(e.span), e.message),
+            source.to_owned(),
+        ),
     };
     let stdout: String = output.result.borrow().clone();
-    (stdout, stderr)
+    (stdout, stderr, edited)
 }
 
 fn check_program_run(
     path: &Path,
     stdout_path: Option<&Path>,
     stderr_path: Option<&Path>,
+    edited_path: Option<&Path>,
+    run_comments: bool,
 ) {
     let source = std::fs::read_to_string(path)
-        .expect(&format!("failed to read {:?}", path));
+        .unwrap_or_else(|_| panic!("failed to read {:?}", path));
     let stdout = match stdout_path {
         Some(path) => std::fs::read_to_string(path)
-            .expect(&format!("failed to read {:?}", path))
+            .unwrap_or_else(|_| panic!("failed to read {:?}", path))
             .replace("\r\n", "\n"),
         None => "".to_owned(),
     };
     let stderr = match stderr_path {
         Some(path) => std::fs::read_to_string(path)
-            .expect(&format!("failed to read {:?}", path))
+            .unwrap_or_else(|_| panic!("failed to read {:?}", path))
             .replace("\r\n", "\n"),
         None => "".to_owned(),
     };
-    let (actual_out, actual_err) = do_run(&source);
+    let edited = match edited_path {
+        Some(path) => std::fs::read_to_string(path)
+            .unwrap_or_else(|_| panic!("failed to read {:?}", path))
+            .replace("\r\n", "\n"),
+        None => source.clone(),
+    };
+    let options = tontuna::EvalOptions {
+        allow_edits: edited_path.is_some(),
+        run_comments,
+    };
+    let (actual_out, actual_err, actual_edited) = with_program_stack(|| do_run(&source, &options));
     if actual_err != stderr {
         panic!(
             "program {} gave incorrect error, expected {:?}, got {:?}",
@@ -102,6 +138,74 @@ fn check_program_run(
             actual_out,
         );
     }
+    if actual_edited != edited {
+        panic!(
+            "program {} made incorrect edits, expected {:?}, got {:?}",
+            path.display(),
+            edited,
+            actual_edited,
+        );
+    }
+}
+
+// Compares problems found by `--check` with the expected ones, one per line.
+fn check_program_check(path: &Path, expected_path: Option<&Path>) {
+    let source = std::fs::read_to_string(path)
+        .unwrap_or_else(|_| panic!("failed to read {:?}", path));
+    let expected = match expected_path {
+        Some(path) => std::fs::read_to_string(path)
+            .unwrap_or_else(|_| panic!("failed to read {:?}", path))
+            .replace("\r\n", "\n"),
+        None => "".to_owned(),
+    };
+    let src = tontuna::Source::new(source.clone());
+    let actual = match tontuna::parse(&source) {
+        Ok(ast) => tontuna::check(&ast)
+            .iter()
+            .map(|e| format!("check error at line {}: {}", src.span_start_line(e.span), e.message))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Err(_) => "".to_owned(),
+    };
+    if actual != expected {
+        panic!(
+            "program {} gave incorrect check results, expected {:?}, got {:?}",
+            path.display(),
+            expected,
+            actual,
+        );
+    }
+}
+
+// Compares the diff that `--dry-run` shows for the requested edits with the
+// expected one.
+fn check_program_diff(path: &Path, diff_path: &Path) {
+    let source = std::fs::read_to_string(path)
+        .unwrap_or_else(|_| panic!("failed to read {:?}", path));
+    let expected = std::fs::read_to_string(diff_path)
+        .unwrap_or_else(|_| panic!("failed to read {:?}", diff_path))
+        .replace("\r\n", "\n");
+    let options = tontuna::EvalOptions {
+        allow_edits: true,
+        run_comments: false,
+    };
+    let edits = with_program_stack(|| {
+        let ast = tontuna::parse(&source).unwrap();
+        let output = SharedSink {
+            result: Default::default(),
+        };
+        tontuna::eval_with_options(&ast, Box::new(output), &options).unwrap()
+    });
+    let file = path.file_name().unwrap().to_string_lossy();
+    let actual = tontuna::edits_diff(&file, &source, &edits);
+    if actual != expected {
+        panic!(
+            "program {} gave incorrect diff of edits, expected {:?}, got {:?}",
+            path.display(),
+            expected,
+            actual,
+        );
+    }
 }
 
 fn file_exists(path: &Path) -> bool {
@@ -134,12 +238,48 @@ fn test_cases() {
         }
         let stdout_path = add_extension(&path, "stdout");
         let stderr_path = add_extension(&path, "stderr");
-        let stdout_path = file_exists(&stdout_path).then(|| stdout_path);
-        let stderr_path = file_exists(&stderr_path).then(|| stderr_path);
-        check_program_run(&path, stdout_path.as_deref(), stderr_path.as_deref());
+        let edited_path = add_extension(&path, "edited");
+        let stdout_path = file_exists(&stdout_path).then_some(stdout_path);
+        let stderr_path = file_exists(&stderr_path).then_some(stderr_path);
+        let edited_path = file_exists(&edited_path).then_some(edited_path);
+        check_program_run(
+            &path,
+            stdout_path.as_deref(),
+            stderr_path.as_deref(),
+            edited_path.as_deref(),
+            false,
+        );
+        let diff_path = add_extension(&path, "diff");
+        let diff_path = file_exists(&diff_path).then_some(diff_path);
+        if let Some(diff_path) = &diff_path {
+            check_program_diff(&path, diff_path);
+        }
+        // programs can have separate expected results for a run with
+        // comment snippets enabled
+        let comments_stdout_path = add_extension(&path, "run-comments.stdout");
+        let comments_stderr_path = add_extension(&path, "run-comments.stderr");
+        let comments_stdout_path = file_exists(&comments_stdout_path).then_some(comments_stdout_path);
+        let comments_stderr_path = file_exists(&comments_stderr_path).then_some(comments_stderr_path);
+        if comments_stdout_path.is_some() || comments_stderr_path.is_some() {
+            check_program_run(
+                &path,
+                comments_stdout_path.as_deref(),
+                comments_stderr_path.as_deref(),
+                None,
+                true,
+            );
+        }
+        let check_path = add_extension(&path, "check");
+        let check_path = file_exists(&check_path).then_some(check_path);
+        check_program_check(&path, check_path.as_deref());
         used_files.insert(path);
+        used_files.extend(check_path);
         used_files.extend(stdout_path);
         used_files.extend(stderr_path);
+        used_files.extend(edited_path);
+        used_files.extend(diff_path);
+        used_files.extend(comments_stdout_path);
+        used_files.extend(comments_stderr_path);
     }
     for file in &seen_files {
         if !used_files.contains(file) {
@@ -4,11 +4,17 @@ use crate::utils::LocationTranslator;
 pub fn get_diagnostics(source: &str) -> Vec<Diagnostic> {
     let mut translator = LocationTranslator::for_source(source);
 
-    tontuna::parse(source)
-        .err()
+    // broken links don't stop the program from running, so they are only
+    // warnings
+    let (errors, severity) = match tontuna::parse(source) {
+        Ok(ast) => (tontuna::check(&ast), DiagnosticSeverity::Warning),
+        Err(e) => (vec![e], DiagnosticSeverity::Error),
+    };
+    errors
+        .into_iter()
         .map(|e| Diagnostic {
             range: translator.to_lsp(e.span),
-            severity: Some(DiagnosticSeverity::Error),
+            severity: Some(severity),
             code: None,
             code_description: None,
             source: Some("ticc".to_owned()),
@@ -17,6 +23,5 @@ pub fn get_diagnostics(source: &str) -> Vec<Diagnostic> {
             tags: None,
             data: None,
         })
-        .into_iter()
         .collect()
 }
@@ -1,4 +1,4 @@
-use std::rc::Rc;
+use std::{cell::{OnceCell, RefCell}, rc::{Rc, Weak}};
 
 use crate::Span;
 pub(crate) use crate::lexer::TokenKind;
@@ -33,42 +33,43 @@ pub(crate) enum Expr {
         tok: Token,
     },
     Call {
-        func: Box<Expr>,
+        func: Rc<Expr>,
         left_paren: Token,
-        args: CommaList<Expr>,
+        args: CommaList<Rc<Expr>>,
         right_paren: Token,
     },
     Paren {
         left_paren: Token,
-        inner: Box<Expr>,
+        inner: Rc<Expr>,
         right_paren: Token,
     },
     PrefixOp {
         operator: Token,
-        arg: Box<Expr>,
+        arg: Rc<Expr>,
     },
     BinOp {
-        lhs: Box<Expr>,
+        lhs: Rc<Expr>,
         operator: Token,
-        rhs: Box<Expr>,
+        rhs: Rc<Expr>,
     },
     Field {
-        obj: Box<Expr>,
+        obj: Rc<Expr>,
         dot: Token,
         field: Token,
     },
     AssignVar {
         name: Token,
         eq: Token,
-        value: Box<Expr>,
+        value: Rc<Expr>,
     },
     AssignField {
-        obj: Box<Expr>,
+        obj: Rc<Expr>,
         dot: Token,
         field: Token,
         eq: Token,
-        value: Box<Expr>,
+        value: Rc<Expr>,
     },
+    ListComp(Box<ListComp>),
 }
 
 impl Expr {
@@ -87,10 +88,25 @@ impl Expr {
             Expr::Field { obj, field, .. } => obj.span().merge(field.span),
             Expr::AssignVar { name, value, .. } => name.span.merge(value.span()),
             Expr::AssignField { obj, value, .. } => obj.span().merge(value.span()),
+            Expr::ListComp(c) => c.left_bracket.span.merge(c.right_bracket.span),
         }
     }
 }
 
+#[derive(Debug, Clone)]
+pub(crate) struct ListComp {
+    pub(crate) left_bracket: Token,
+    pub(crate) item: Rc<Expr>,
+    pub(crate) for_tok: Token,
+    pub(crate) name: Token,
+    pub(crate) in_tok: Token,
+    pub(crate) iterable: Rc<Expr>,
+    pub(crate) if_tok: Option<Token>,
+    // condition after `if`
+    pub(crate) filter: Option<Rc<Expr>>,
+    pub(crate) right_bracket: Token,
+}
+
 pub(crate) type CommaList<T> = Vec<ListItem<T>>;
 
 #[derive(Debug, Clone)]
@@ -113,27 +129,34 @@ pub(crate) enum Stmt {
         body: Block,
     },
     Expr {
-        expr: Expr,
+        expr: Rc<Expr>,
         semi: Token,
     },
     For {
         for_tok: Token,
         name: Token,
         in_tok: Token,
-        iterable: Expr,
+        iterable: Rc<Expr>,
         body: Block,
     },
     Return {
         ret: Token,
-        value: Option<Expr>,
+        value: Option<Rc<Expr>>,
+        semi: Token,
+    },
+    Yield {
+        yield_tok: Token,
+        value: Option<Rc<Expr>>,
         semi: Token,
     },
     Let {
         let_tok: Token,
         name: Token,
         eq: Token,
-        value: Expr,
+        value: Rc<Expr>,
         semi: Token,
+        // comment documenting this definition, set once while parsing
+        doc: OnceCell<Rc<Stmt>>,
     },
     Comment(Comment),
     FnDef(Rc<FnDef>),
@@ -141,8 +164,11 @@ pub(crate) enum Stmt {
         struct_tok: Token,
         name: Token,
         left_curly: Token,
-        fns: Vec<FnDef>,
+        // `FnDef` statements of the methods
+        fns: Vec<Rc<Stmt>>,
         right_curly: Token,
+        // comment documenting this definition, set once while parsing
+        doc: OnceCell<Rc<Stmt>>,
     },
     Block(Block),
 }
@@ -158,6 +184,7 @@ impl Stmt {
             Stmt::Expr { expr, semi } => expr.span().merge(semi.span),
             Stmt::For { for_tok, body, .. } => for_tok.span.merge(body.span()),
             Stmt::Return { ret, semi, .. } => ret.span.merge(semi.span),
+            Stmt::Yield { yield_tok, semi, .. } => yield_tok.span.merge(semi.span),
             Stmt::Let { let_tok, semi, .. } => let_tok.span.merge(semi.span),
             Stmt::Comment(c) => c.span(),
             Stmt::FnDef(d) => d.span(),
@@ -165,18 +192,28 @@ impl Stmt {
             Stmt::Block(b) => b.span(),
         }
     }
+
+    // comment directly preceding a definition
+    pub(crate) fn doc(&self) -> Option<&Rc<Stmt>> {
+        match self {
+            Stmt::Let { doc, .. } |
+            Stmt::StructDef { doc, .. } => doc.get(),
+            Stmt::FnDef(d) => d.doc.get(),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub(crate) enum IfCond {
-    Expr(Expr),
+    Expr(Rc<Expr>),
     TypeTest {
         let_tok: Token,
         name: Token,
         colon: Token,
-        ty: Expr,
+        ty: Rc<Expr>,
         eq: Token,
-        value: Expr,
+        value: Rc<Expr>,
     },
 }
 
@@ -217,6 +254,10 @@ pub(crate) struct FnDef {
     pub(crate) params: CommaList<Token>,
     pub(crate) right_paren: Token,
     pub(crate) body: Block,
+    // calling a function that contains `yield` creates a generator
+    pub(crate) is_generator: bool,
+    // comment documenting this definition, set once while parsing
+    pub(crate) doc: OnceCell<Rc<Stmt>>,
 }
 
 impl FnDef {
@@ -240,7 +281,7 @@ impl Block {
 
 #[derive(Debug, Clone)]
 pub(crate) struct NakedBlock {
-    pub(crate) stmts: Vec<Rc<Stmt>>,
+    pub(crate) stmts: Rc<[Rc<Stmt>]>,
 }
 
 impl NakedBlock {
@@ -254,6 +295,8 @@ pub(crate) struct Comment {
     // can be interleaved with or even inside comment elements
     pub(crate) markers: Vec<Token>,
     pub(crate) elements: Vec<CommentElem>,
+    // definition documented by this comment, filled in once it is parsed
+    pub(crate) target: RefCell<Weak<Stmt>>,
 }
 
 impl Comment {
@@ -275,21 +318,79 @@ impl Comment {
 
 #[derive(Debug, Clone)]
 pub(crate) enum CommentElem {
-    Text(Token),
+    Text {
+        text: Token,
+        // `[name]` references to top-level definitions
+        links: Vec<Link>,
+    },
     Code {
         // can be inside code contents
         markers: Vec<Token>,
+        // written on the first line of the snippet, e.g. `# > @ignore`
+        attributes: Vec<Attribute>,
         code: NakedBlock,
+        // markers of lines that run but aren't shown, e.g. `# >> setup();`,
+        // they are in `markers` too
+        hidden: Vec<Token>,
+        // text lines directly after the snippet starting with `=>`
+        expected_output: Vec<Token>,
     },
 }
 
+#[derive(Debug, Clone)]
+pub(crate) struct Link {
+    pub(crate) name: Token,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Attribute {
+    pub(crate) kind: SnippetAttr,
+    pub(crate) span: Span,
+}
+
+// Tells tools running snippets how to treat them.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum SnippetAttr {
+    ShouldPanic,
+    Ignore,
+    NoRun,
+    CompileOnly,
+}
+
+impl SnippetAttr {
+    pub(crate) const ALL: [SnippetAttr; 4] = [
+        SnippetAttr::ShouldPanic,
+        SnippetAttr::Ignore,
+        SnippetAttr::NoRun,
+        SnippetAttr::CompileOnly,
+    ];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            SnippetAttr::ShouldPanic => "should_panic",
+            SnippetAttr::Ignore => "ignore",
+            SnippetAttr::NoRun => "no_run",
+            SnippetAttr::CompileOnly => "compile_only",
+        }
+    }
+
+    pub(crate) fn from_name(name: &str) -> Option<SnippetAttr> {
+        SnippetAttr::ALL.iter().copied().find(|attr| attr.name() == name)
+    }
+}
+
 impl CommentElem {
     pub(crate) fn span(&self) -> Option<Span> {
         match self {
-            CommentElem::Text(t) => Some(t.span),
-            CommentElem::Code { markers, code } => {
-                let start = markers.iter().map(|t| t.span.start).min();
-                let end = markers.iter().map(|t| t.span.start).max();
+            CommentElem::Text { text, .. } => Some(text.span),
+            CommentElem::Code { markers, attributes, code, expected_output, .. } => {
+                let spans = markers
+                    .iter()
+                    .chain(expected_output)
+                    .map(|t| t.span)
+                    .chain(attributes.iter().map(|a| a.span));
+                let start = spans.clone().map(|s| s.start).min();
+                let end = spans.map(|s| s.end).max();
                 let code = code.span();
                 let markers = match (start, end) {
                     (Some(s), Some(e)) => Some(Span::new(s, e)),
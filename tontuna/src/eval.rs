@@ -1,9 +1,9 @@
 mod intrinsics;
 mod types;
 
-use std::{cell::RefCell, collections::HashMap, io::Write, rc::Rc};
+use std::{cell::{Cell, RefCell}, collections::HashMap, io::Write, rc::Rc};
 use crate::{ast::{self, TokenKind}, Source, Span};
-use self::types::{Instance, Interpreter, List, NativeFunc, Stmt, Str, Struct, UserFunc};
+use self::types::{Instance, Generator, GeneratorState, Interpreter, List, NativeFunc, CommentElement, Error, Expr, RunResult, Set, SetKey, SourceSpan, Stmt, Str, Struct, UserFunc};
 
 #[derive(Clone)]
 pub(crate) enum Value {
@@ -15,9 +15,16 @@ pub(crate) enum Value {
     Struct(Rc<Struct>),
     Instance(Rc<Instance>),
     List(Rc<List>),
+    Set(Rc<Set>),
     UserFunc(Rc<UserFunc>),
     Stmt(Rc<Stmt>),
     Interpreter(Rc<Interpreter>),
+    Generator(Rc<Generator>),
+    Span(Rc<SourceSpan>),
+    CommentElement(Rc<CommentElement>),
+    Expr(Rc<Expr>),
+    Error(Rc<Error>),
+    Result(Rc<RunResult>),
 }
 
 impl From<NativeFunc> for Value {
@@ -61,20 +68,36 @@ impl Value {
             Value::Struct(_) => "Struct".to_owned(),
             Value::Instance(i) => i.ty.name.clone(),
             Value::List(_) => "List".to_owned(),
+            Value::Set(_) => "Set".to_owned(),
             Value::UserFunc(_) => "Fn".to_owned(),
             Value::Stmt(s) if s.is_code() => "Code".to_owned(),
             Value::Stmt(_) => "Comment".to_owned(),
             Value::Interpreter(_) => "Interpreter".to_owned(),
+            Value::Generator(_) => "Generator".to_owned(),
+            Value::Span(_) => "Span".to_owned(),
+            Value::CommentElement(e) if e.is_snippet() => "Snippet".to_owned(),
+            Value::CommentElement(_) => "Text".to_owned(),
+            Value::Expr(_) => "Expr".to_owned(),
+            Value::Error(_) => "Error".to_owned(),
+            Value::Result(_) => "Result".to_owned(),
         }
     }
 
     fn lookup_field(&self, field: &str) -> Option<Value> {
         match self {
             Value::Str(s) => s.lookup_field(self, field),
-            Value::Instance(i) => i.lookup_field(field),
+            Value::Instance(i) => i.lookup_field(self, field),
             Value::List(l) => l.lookup_field(self, field),
-            Value::Stmt(s) => s.lookup_field(field),
+            Value::Set(s) => s.lookup_field(self, field),
+            Value::UserFunc(f) => f.lookup_field(field),
+            Value::Struct(s) => s.lookup_field(field),
+            Value::Stmt(s) => s.lookup_field(self, field),
             Value::Interpreter(s) => s.lookup_field(self, field),
+            Value::Span(s) => s.lookup_field(field),
+            Value::CommentElement(e) => e.lookup_field(self, field),
+            Value::Expr(e) => e.lookup_field(field),
+            Value::Error(e) => e.lookup_field(field),
+            Value::Result(r) => r.lookup_field(field),
             _ => None,
         }
     }
@@ -84,9 +107,16 @@ impl Value {
             Value::Str(_) => Err("Str fields cannot be modified".to_owned()),
             Value::Instance(i) => Ok(i.set_field(field, value)),
             Value::List(_) => Err("List fields cannot be modified".to_owned()),
+            Value::Set(_) => Err("Set fields cannot be modified".to_owned()),
             Value::Stmt(s) if s.is_code() => Err("Code fields cannot be modified".to_owned()),
             Value::Stmt(_) => Err("Comment fields cannot be modified".to_owned()),
             Value::Interpreter(_) => Err("Interpreter fields cannot be modified".to_owned()),
+            Value::Span(_) => Err("Span fields cannot be modified".to_owned()),
+            Value::CommentElement(e) if e.is_snippet() => Err("Snippet fields cannot be modified".to_owned()),
+            Value::CommentElement(_) => Err("Text fields cannot be modified".to_owned()),
+            Value::Expr(_) => Err("Expr fields cannot be modified".to_owned()),
+            Value::Error(_) => Err("Error fields cannot be modified".to_owned()),
+            Value::Result(_) => Err("Result fields cannot be modified".to_owned()),
             _ => Err(format!("{} cannot have fields", self.type_name())),
         }
     }
@@ -101,10 +131,22 @@ impl Value {
             Value::Struct(s) => format!("<Struct {}>", s.name),
             Value::Instance(i) => format!("<{}>", i.ty.name),
             Value::List(_) => "<List>".to_owned(),
+            Value::Set(_) => "<Set>".to_owned(),
             Value::UserFunc(f) => format!("<Fn {}>", f.name),
             Value::Stmt(s) if s.is_code() => "<Code>".to_owned(),
             Value::Stmt(_) => "<Comment>".to_owned(),
             Value::Interpreter(_) => "<Interpreter>".to_owned(),
+            Value::Generator(g) => format!("<Generator {}>", g.name),
+            Value::Span(s) => format!(
+                "<Span {}:{}>",
+                s.source.pos_line(s.span.start),
+                s.source.pos_column(s.span.start),
+            ),
+            Value::CommentElement(e) if e.is_snippet() => "<Snippet>".to_owned(),
+            Value::CommentElement(_) => "<Text>".to_owned(),
+            Value::Expr(e) => format!("<Expr {}>", intrinsics::expr_kind(&e.ast)),
+            Value::Error(e) => format!("<Error {}>", e.message),
+            Value::Result(_) => "<Result>".to_owned(),
         }
     }
 }
@@ -119,12 +161,154 @@ enum EvalStop {
     Return(Value),
 }
 
+// Calls nested deeper than this could overflow the stack of the thread
+// running the program (a debug build uses about 20KB of stack per call), so
+// no evaluator goes past it, whatever its own limits are.
+const MAX_CALL_DEPTH: u64 = 200;
+
+// Resource limits of a sandboxed `Interpreter` along with the usage they are
+// checked against. Only `MAX_CALL_DEPTH` applies by default.
+#[derive(Default)]
+pub(crate) struct Limits {
+    pub(crate) max_steps: Option<u64>,
+    pub(crate) max_call_depth: Option<u64>,
+    pub(crate) max_output: Option<u64>,
+    pub(crate) max_size: Option<u64>,
+    steps: u64,
+    output: u64,
+    // set once a limit is exceeded, so that the resulting error can be told
+    // apart from other runtime errors
+    pub(crate) exceeded: bool,
+}
+
+impl Limits {
+    pub(crate) fn reset_usage(&mut self) {
+        self.steps = 0;
+        self.output = 0;
+        self.exceeded = false;
+    }
+
+    // Same limits, without any usage counted.
+    pub(crate) fn fresh(&self) -> Limits {
+        Limits {
+            max_steps: self.max_steps,
+            max_call_depth: self.max_call_depth,
+            max_output: self.max_output,
+            max_size: self.max_size,
+            ..Limits::default()
+        }
+    }
+
+    fn exceed(&mut self, message: String) -> Result<(), String> {
+        self.exceeded = true;
+        Err(message)
+    }
+
+    fn count_step(&mut self) -> Result<(), String> {
+        self.steps += 1;
+        match self.max_steps {
+            Some(max) if self.steps > max => self.exceed(format!("step limit of {} exceeded", max)),
+            _ => Ok(()),
+        }
+    }
+
+    // `depth` counts calls of this evaluator, `total_depth` also counts
+    // calls of the evaluators running it, they share the same stack.
+    fn check_call_depth(&mut self, depth: u64, total_depth: u64) -> Result<(), String> {
+        match self.max_call_depth {
+            Some(max) if depth >= max => self.exceed(format!("call depth limit of {} exceeded", max)),
+            _ if total_depth >= MAX_CALL_DEPTH => {
+                self.exceed(format!("call depth limit of {} exceeded", MAX_CALL_DEPTH))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn count_output(&mut self, values: &[Value], newline: bool) -> Result<(), String> {
+        let max = match self.max_output {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        let len = values.iter().map(|v| v.stringify().chars().count() as u64).sum::<u64>();
+        self.output += len + newline as u64;
+        if self.output > max {
+            return self.exceed(format!("output limit of {} characters exceeded", max));
+        }
+        Ok(())
+    }
+
+    fn check_size(&mut self, value: &Value) -> Result<(), String> {
+        let max = match self.max_size {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        let len = match value {
+            Value::Str(s) => s.chars.len(),
+            Value::List(l) => l.values.borrow().len(),
+            Value::Set(s) => s.len(),
+            _ => return Ok(()),
+        };
+        if len as u64 > max {
+            return self.exceed(format!(
+                "{} of length {} exceeds the size limit of {}",
+                value.type_name(),
+                len,
+                max,
+            ));
+        }
+        Ok(())
+    }
+}
+
 impl From<RuntimeError> for EvalStop {
     fn from(v: RuntimeError) -> Self {
         Self::Error(v)
     }
 }
 
+// Statement execution state that can be suspended. Each `UserFunc` call
+// runs its body on a separate stack of frames, so that generators can keep
+// theirs around between `yield`s.
+pub(crate) enum Frame {
+    Block {
+        stmts: Rc<[Rc<ast::Stmt>]>,
+        next: usize,
+        env: Env,
+    },
+    While {
+        stmt: Rc<ast::Stmt>,
+        env: Env,
+    },
+    For {
+        name: String,
+        iter: Iter,
+        span: Span,
+        body: Rc<[Rc<ast::Stmt>]>,
+        env: Env,
+    },
+}
+
+impl Frame {
+    fn block(block: &ast::NakedBlock, env: &Env) -> Frame {
+        Frame::Block {
+            stmts: block.stmts.clone(),
+            next: 0,
+            env: env.with_fence(),
+        }
+    }
+}
+
+pub(crate) enum Iter {
+    Values(std::vec::IntoIter<Value>),
+    Generator(Rc<Generator>),
+}
+
+enum Step {
+    Next(Env),
+    Push(Vec<Frame>),
+    Yield(Value),
+}
+
 pub(crate) struct EnvEntry {
     name: String,
     value: RefCell<Value>,
@@ -159,6 +343,68 @@ impl Env {
         }
     }
 
+    // Names visible in this environment, inner definitions shadow outer ones.
+    fn names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        let mut this = self;
+        loop {
+            match this {
+                Env::Chain(entry) => {
+                    names.push(entry.name.clone());
+                    this = &entry.next;
+                }
+                Env::GlobalFence(env) => this = env,
+                Env::Global(globals) => {
+                    names.extend(globals.borrow().keys().cloned());
+                    break;
+                }
+            }
+        }
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    // Copies globals of `other` which aren't defined in this global env.
+    fn copy_missing(&self, other: &Env) {
+        if let (Env::Global(globals), Env::Global(other)) = (self, other) {
+            let mut globals = globals.borrow_mut();
+            for (name, value) in other.borrow().iter() {
+                globals.entry(name.clone()).or_insert_with(|| value.clone());
+            }
+        }
+    }
+
+    // Copies all globals of `other`, including the ones shadowing builtins.
+    // Lists, sets, instances and generators are copied, and functions
+    // defined in `other` are rebound to this env, so they see its bindings
+    // instead of the original ones.
+    fn fork_from(&self, other: &Env) -> Result<(), String> {
+        if let (Env::Global(globals), Env::Global(other_globals)) = (self, other) {
+            let mut fork = Fork {
+                from: other_globals.clone(),
+                to: self.clone(),
+                copies: HashMap::new(),
+                envs: HashMap::new(),
+            };
+            let values = other_globals.borrow().clone();
+            for (name, value) in values {
+                let value = fork.value(&value)?;
+                globals.borrow_mut().insert(name, value);
+            }
+        }
+        Ok(())
+    }
+
+    fn same_as(&self, other: &Env) -> bool {
+        match (self, other) {
+            (Env::Chain(a), Env::Chain(b)) => Rc::ptr_eq(a, b),
+            (Env::GlobalFence(a), Env::GlobalFence(b)) => Rc::ptr_eq(a, b),
+            (Env::Global(a), Env::Global(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+
     fn with_fence(&self) -> Env {
         match self {
             Env::Chain(_) |
@@ -212,56 +458,226 @@ impl Env {
     }
 }
 
+// Copies values reachable from the globals of a forked interpreter. Each value
+// is copied once, so values shared by several globals stay shared in the fork.
+struct Fork {
+    from: Rc<RefCell<HashMap<String, Value>>>,
+    to: Env,
+    // copies by address of the original
+    copies: HashMap<usize, Value>,
+    envs: HashMap<usize, Env>,
+}
+
+impl Fork {
+    // Lists, instances and generators are registered before their contents
+    // are copied, so that they can contain themselves.
+    fn value(&mut self, value: &Value) -> Result<Value, String> {
+        match value {
+            Value::List(list) => {
+                let key = Rc::as_ptr(list) as usize;
+                if let Some(copy) = self.copies.get(&key) {
+                    return Ok(copy.clone());
+                }
+                let copy = Rc::new(List::new(Vec::new()));
+                self.copies.insert(key, Value::List(copy.clone()));
+                let values = list.values.borrow().clone();
+                let values = values.iter().map(|v| self.value(v)).collect::<Result<_, _>>()?;
+                *copy.values.borrow_mut() = values;
+                Ok(Value::List(copy))
+            }
+            Value::Set(set) => {
+                let key = Rc::as_ptr(set) as usize;
+                Ok(self.copies
+                    .entry(key)
+                    .or_insert_with(|| Value::Set(Rc::new(Set::new(set.keys()))))
+                    .clone())
+            }
+            Value::Instance(instance) => {
+                let key = Rc::as_ptr(instance) as usize;
+                if let Some(copy) = self.copies.get(&key) {
+                    return Ok(copy.clone());
+                }
+                let copy = Rc::new(Instance {
+                    ty: instance.ty.clone(),
+                    fields: Default::default(),
+                });
+                self.copies.insert(key, Value::Instance(copy.clone()));
+                let fields = instance.fields.borrow().clone();
+                let fields = fields
+                    .into_iter()
+                    .map(|(name, v)| Ok((name, self.value(&v)?)))
+                    .collect::<Result<_, String>>()?;
+                *copy.fields.borrow_mut() = fields;
+                Ok(Value::Instance(copy))
+            }
+            Value::UserFunc(f) => {
+                let key = Rc::as_ptr(f) as usize;
+                if let Some(copy) = self.copies.get(&key) {
+                    return Ok(copy.clone());
+                }
+                let copy = Value::UserFunc(Rc::new(UserFunc {
+                    name: f.name.clone(),
+                    source: f.source.clone(),
+                    def: f.def.clone(),
+                    stmt: f.stmt.clone(),
+                    env: self.env(&f.env)?,
+                }));
+                self.copies.insert(key, copy.clone());
+                Ok(copy)
+            }
+            Value::Generator(gen) => Ok(Value::Generator(self.generator(gen)?)),
+            _ => Ok(value.clone()),
+        }
+    }
+
+    // A suspended generator continues from the same point in both
+    // interpreters, independently of each other.
+    fn generator(&mut self, gen: &Rc<Generator>) -> Result<Rc<Generator>, String> {
+        let key = Rc::as_ptr(gen) as usize;
+        if let Some(Value::Generator(copy)) = self.copies.get(&key) {
+            return Ok(copy.clone());
+        }
+        let copy = Rc::new(Generator {
+            name: gen.name.clone(),
+            source: gen.source.clone(),
+            state: RefCell::new(GeneratorState::Finished),
+        });
+        self.copies.insert(key, Value::Generator(copy.clone()));
+        let state = match &*gen.state.borrow() {
+            GeneratorState::Suspended(frames) => GeneratorState::Suspended(
+                frames.iter().map(|frame| self.frame(frame)).collect::<Result<_, _>>()?,
+            ),
+            GeneratorState::Running => {
+                return Err(format!("cannot fork while generator `{}` is running", gen.name));
+            }
+            GeneratorState::Finished => GeneratorState::Finished,
+        };
+        *copy.state.borrow_mut() = state;
+        Ok(copy)
+    }
+
+    fn frame(&mut self, frame: &Frame) -> Result<Frame, String> {
+        Ok(match frame {
+            Frame::Block { stmts, next, env } => Frame::Block {
+                stmts: stmts.clone(),
+                next: *next,
+                env: self.env(env)?,
+            },
+            Frame::While { stmt, env } => Frame::While {
+                stmt: stmt.clone(),
+                env: self.env(env)?,
+            },
+            Frame::For { name, iter, span, body, env } => Frame::For {
+                name: name.clone(),
+                iter: match iter {
+                    Iter::Values(values) => Iter::Values(values
+                        .as_slice()
+                        .iter()
+                        .map(|v| self.value(v))
+                        .collect::<Result<Vec<_>, _>>()?
+                        .into_iter()),
+                    Iter::Generator(gen) => Iter::Generator(self.generator(gen)?),
+                },
+                span: *span,
+                body: body.clone(),
+                env: self.env(env)?,
+            },
+        })
+    }
+
+    // Environments of functions defined in blocks are copied down to the
+    // forked globals, environments of other interpreters are kept.
+    fn env(&mut self, env: &Env) -> Result<Env, String> {
+        match env {
+            Env::Chain(entry) => {
+                let key = Rc::as_ptr(entry) as usize;
+                if let Some(copy) = self.envs.get(&key) {
+                    return Ok(copy.clone());
+                }
+                let next = self.env(&entry.next)?;
+                let copy = if next.same_as(&entry.next) {
+                    env.clone()
+                } else {
+                    let value = self.value(&entry.value.borrow())?;
+                    Env::Chain(Rc::new(EnvEntry {
+                        name: entry.name.clone(),
+                        value: value.into(),
+                        next,
+                    }))
+                };
+                self.envs.insert(key, copy.clone());
+                Ok(copy)
+            }
+            Env::GlobalFence(inner) => {
+                let copy = self.env(inner)?;
+                if copy.same_as(inner) {
+                    Ok(env.clone())
+                } else {
+                    Ok(Env::GlobalFence(Rc::new(copy)))
+                }
+            }
+            Env::Global(globals) if Rc::ptr_eq(globals, &self.from) => Ok(self.to.clone()),
+            Env::Global(_) => Ok(env.clone()),
+        }
+    }
+}
+
 struct BuiltinTypes {
     nil: Rc<Struct>,
     int: Rc<Struct>,
     bool: Rc<Struct>,
     str: Rc<Struct>,
     list: Rc<Struct>,
+    set: Rc<Struct>,
     strukt: Rc<Struct>,
     func: Rc<Struct>,
     code: Rc<Struct>,
     comment: Rc<Struct>,
     interpreter: Rc<Struct>,
+    generator: Rc<Struct>,
+    span: Rc<Struct>,
+    text: Rc<Struct>,
+    snippet: Rc<Struct>,
+    expr: Rc<Struct>,
+    error: Rc<Struct>,
+    result: Rc<Struct>,
     all: Vec<Rc<Struct>>,
 }
 
 impl BuiltinTypes {
     fn new() -> Self {
         fn make_ty(name: &str) -> Rc<Struct> {
-            Rc::new(Struct {
-                name: name.to_owned(),
-                ctor: Some(Rc::new(NativeFunc::new(name, |_| {
-                    Err(intrinsics::invalid_ctor())
-                }))),
-            })
+            Struct::builtin(name, NativeFunc::new(name, |_| {
+                Err(intrinsics::invalid_ctor())
+            }))
         }
         let mut builtins = BuiltinTypes {
             nil: make_ty("Nil"),
             int: make_ty("Int"),
             bool: make_ty("Bool"),
-            str: Rc::new(Struct {
-                name: "Str".to_owned(),
-                ctor: Some(Rc::new(NativeFunc::new("Str", |values| {
-                    Ok(intrinsics::str_ctor(values))
-                }))),
-            }),
-            list: Rc::new(Struct {
-                name: "List".to_owned(),
-                ctor: Some(Rc::new(NativeFunc::new("List", |values| {
-                    Ok(intrinsics::list_ctor(values))
-                }))),
-            }),
+            str: Struct::builtin("Str", NativeFunc::new("Str", |values| {
+                Ok(intrinsics::str_ctor(values))
+            })),
+            list: Struct::builtin("List", NativeFunc::new("List", |values| {
+                Ok(intrinsics::list_ctor(values))
+            })),
+            set: Struct::builtin("Set", NativeFunc::new("Set", |values| {
+                intrinsics::set_ctor(values)
+            })),
             strukt: make_ty("Struct"),
             func: make_ty("Fn"),
             code: make_ty("Code"),
             comment: make_ty("Comment"),
-            interpreter: Rc::new(Struct {
-                name: "Interpreter".to_owned(),
-                ctor: Some(Rc::new(NativeFunc::new_src_hack("Interpreter", |src| {
-                    Ok(Interpreter::new(src))
-                }))),
-            }),
+            interpreter: Struct::builtin("Interpreter", NativeFunc::with_evaluator("Interpreter", |eval, values| {
+                intrinsics::interpreter_ctor(eval, values)
+            })),
+            generator: make_ty("Generator"),
+            span: make_ty("Span"),
+            text: make_ty("Text"),
+            snippet: make_ty("Snippet"),
+            expr: make_ty("Expr"),
+            error: make_ty("Error"),
+            result: make_ty("Result"),
             all: Vec::new(),
         };
         builtins.all = vec![
@@ -270,42 +686,124 @@ impl BuiltinTypes {
             builtins.bool.clone(),
             builtins.str.clone(),
             builtins.list.clone(),
+            builtins.set.clone(),
             builtins.strukt.clone(),
             builtins.func.clone(),
             builtins.code.clone(),
             builtins.comment.clone(),
             builtins.interpreter.clone(),
+            builtins.generator.clone(),
+            builtins.span.clone(),
+            builtins.text.clone(),
+            builtins.snippet.clone(),
+            builtins.expr.clone(),
+            builtins.error.clone(),
+            builtins.result.clone(),
         ];
         builtins
     }
+
+    fn value_type(&self, value: &Value) -> Rc<Struct> {
+        match value {
+            Value::Nil => self.nil.clone(),
+            Value::Int(_) => self.int.clone(),
+            Value::Bool(_) => self.bool.clone(),
+            Value::Str(_) => self.str.clone(),
+            Value::NativeFunc(_) => self.func.clone(),
+            Value::Struct(_) => self.strukt.clone(),
+            Value::Instance(i) => i.ty.clone(),
+            Value::List(_) => self.list.clone(),
+            Value::Set(_) => self.set.clone(),
+            Value::UserFunc(_) => self.func.clone(),
+            Value::Stmt(s) => if s.is_code() {
+                self.code.clone()
+            } else {
+                self.comment.clone()
+            },
+            Value::Interpreter(_) => self.interpreter.clone(),
+            Value::Generator(_) => self.generator.clone(),
+            Value::Span(_) => self.span.clone(),
+            Value::CommentElement(e) => if e.is_snippet() {
+                self.snippet.clone()
+            } else {
+                self.text.clone()
+            },
+            Value::Expr(_) => self.expr.clone(),
+            Value::Error(_) => self.error.clone(),
+            Value::Result(_) => self.result.clone(),
+        }
+    }
 }
 
 pub(crate) struct Evaluator {
     source: Rc<Source>,
     globals: Env,
     call_stack_size: u64,
-    builtins: BuiltinTypes,
+    // calls of this evaluator and of the evaluators that created it
+    total_call_depth: Rc<Cell<u64>>,
+    builtins: Rc<BuiltinTypes>,
+    // edits of the program source, only collected when they are allowed
+    edits: Option<Rc<RefCell<Vec<crate::Edit>>>>,
+    limits: Rc<RefCell<Limits>>,
+    output: Rc<RefCell<Box<dyn Write>>>,
+    run_comments: bool,
+    // `call_stack_size` when the innermost running comment snippet started
+    snippet_base: Option<u64>,
 }
 
 impl Evaluator {
-    pub(crate) fn new(source: Rc<Source>, program: Option<&ast::Program>, output: Box<dyn Write>) -> Evaluator {
+    pub(crate) fn new(
+        source: Rc<Source>,
+        program: Option<&ast::Program>,
+        output: Box<dyn Write>,
+        options: &crate::EvalOptions,
+    ) -> Evaluator {
+        Evaluator::with_builtins(source, program, output, options, Rc::new(BuiltinTypes::new()))
+    }
+
+    fn with_builtins(
+        source: Rc<Source>,
+        program: Option<&ast::Program>,
+        output: Box<dyn Write>,
+        options: &crate::EvalOptions,
+        builtins: Rc<BuiltinTypes>,
+    ) -> Evaluator {
         let mut globals = HashMap::new();
+        let edits = options.allow_edits.then(|| Rc::new(RefCell::new(Vec::new())));
+        let limits = Rc::new(RefCell::new(Limits::default()));
         let output = Rc::new(RefCell::new(output));
-        let output2 = output.clone();
-        globals.insert("print".to_owned(), NativeFunc::new("print", move |values| {
-            let mut output = output2.borrow_mut();
+        // output goes to the evaluator running the code, so that functions
+        // copied into an `Interpreter` print to its output
+        globals.insert("print".to_owned(), NativeFunc::with_evaluator("print", |eval, values| {
+            eval.limits.borrow_mut().count_output(values, false)?;
+            let mut output = eval.output.borrow_mut();
             intrinsics::print(values, &mut **output)?;
             Ok(Value::Nil)
         }).into());
-        let output2 = output.clone();
-        globals.insert("println".to_owned(), NativeFunc::new("println", move |values| {
-            let mut output = output2.borrow_mut();
+        globals.insert("println".to_owned(), NativeFunc::with_evaluator("println", |eval, values| {
+            eval.limits.borrow_mut().count_output(values, true)?;
+            let mut output = eval.output.borrow_mut();
             intrinsics::println(values, &mut **output)?;
             Ok(Value::Nil)
         }).into());
         globals.insert("panic".to_owned(), NativeFunc::new("panic", move |values| {
             Err(intrinsics::panic(values))
         }).into());
+        globals.insert("get_field".to_owned(), NativeFunc::new2("get_field", |obj, name| {
+            intrinsics::get_field(obj, name)
+        }).into());
+        globals.insert("set_field".to_owned(), NativeFunc::new3("set_field", |obj, name, value| {
+            intrinsics::set_field(obj, name, value)
+        }).into());
+        globals.insert("has_field".to_owned(), NativeFunc::new2("has_field", |obj, name| {
+            intrinsics::has_field(obj, name)
+        }).into());
+        globals.insert("parse".to_owned(), NativeFunc::new1("parse", |text| {
+            intrinsics::parse(text)
+        }).into());
+        globals.insert("fields".to_owned(), NativeFunc::new1("fields", |obj| {
+            intrinsics::fields(obj)
+        }).into());
         if let Some(program) = program {
             let stmts = Value::List(Rc::new(List::new(
                 program.code.stmts
@@ -322,8 +820,16 @@ impl Evaluator {
                 }
                 Ok(stmts.clone())
             }).into());
+            let source = source.clone();
+            let edits = edits.clone();
+            globals.insert("edit_source".to_owned(), NativeFunc::new2("edit_source", move |target, replacement| {
+                intrinsics::edit_source(&source, edits.as_deref(), target, replacement)
+            }).into());
         }
-        let builtins = BuiltinTypes::new();
+        let builtins2 = builtins.clone();
+        globals.insert("type_of".to_owned(), NativeFunc::new1("type_of", move |value| {
+            Ok(Value::Struct(builtins2.value_type(value)))
+        }).into());
         for value in &builtins.all {
             globals.insert(value.name.clone(), Value::Struct(value.clone()));
         }
@@ -331,71 +837,96 @@ impl Evaluator {
             source,
             globals: Env::global(globals),
             call_stack_size: 0,
+            total_call_depth: Default::default(),
             builtins,
+            edits,
+            limits,
+            output,
+            run_comments: options.run_comments,
+            snippet_base: None,
         }
     }
 
-    fn eval_statement(&mut self, stmt: &ast::Stmt, env: &Env) -> Result<Env, EvalStop> {
-        match stmt {
-            ast::Stmt::While { cond, body, .. } => {
-                loop {
-                    let (is_true, binding) = self.eval_if_cond(cond, env)?;
-                    if is_true {
-                        match binding {
-                            Some((name, value)) => {
-                                let env = env.with_fence().define(self.token_source(name), value);
-                                self.eval_block(&body.contents, &env)?;
-                            }
-                            None => {
-                                self.eval_block(&body.contents, env)?;
-                            }
-                        }
-                    } else {
-                        break;
-                    }
-                }
+    fn check_limit(
+        &self,
+        check: impl FnOnce(&mut Limits) -> Result<(), String>,
+        span: Span,
+    ) -> Result<(), RuntimeError> {
+        check(&mut self.limits.borrow_mut()).map_err(|message| RuntimeError {
+            message,
+            span: Some(span),
+        })
+    }
+
+    fn enter_call(&mut self, span: Span) -> Result<(), RuntimeError> {
+        let depth = self.call_stack_size;
+        let total_depth = self.total_call_depth.get();
+        self.check_limit(|limits| limits.check_call_depth(depth, total_depth), span)?;
+        self.call_stack_size += 1;
+        self.total_call_depth.set(total_depth + 1);
+        Ok(())
+    }
+
+    fn leave_call(&mut self) {
+        self.call_stack_size -= 1;
+        self.total_call_depth.set(self.total_call_depth.get() - 1);
+    }
+
+    pub(crate) fn take_edits(&mut self) -> Vec<crate::Edit> {
+        match &self.edits {
+            Some(edits) => std::mem::take(&mut *edits.borrow_mut()),
+            None => Vec::new(),
+        }
+    }
+
+    // Executes a single statement. Statements with nested blocks don't run
+    // their bodies here, instead they return frames to be run by `run_frames`.
+    fn exec_statement(&mut self, stmt: &Rc<ast::Stmt>, env: &Env) -> Result<Step, EvalStop> {
+        self.check_limit(Limits::count_step, stmt.span())?;
+        match &**stmt {
+            ast::Stmt::While { .. } => {
+                let frame = Frame::While {
+                    stmt: stmt.clone(),
+                    env: env.clone(),
+                };
+                return Ok(Step::Push(vec![frame]));
             }
             ast::Stmt::If { cond, body, tail, .. } => {
-                let (is_true, binding) = self.eval_if_cond(cond, env)?;
-                if is_true {
-                    match binding {
-                        Some((name, value)) => {
-                            let env = env.with_fence().define(self.token_source(name), value);
-                            self.eval_block(&body.contents, &env)?;
+                if let Some(env) = self.enter_if_cond(cond, env)? {
+                    return Ok(Step::Push(vec![Frame::block(&body.contents, &env)]));
+                }
+                let mut tail: &ast::IfTail = tail;
+                loop {
+                    match tail {
+                        ast::IfTail::None => break,
+                        ast::IfTail::Else { body, .. } => {
+                            return Ok(Step::Push(vec![Frame::block(&body.contents, env)]));
                         }
-                        None => {
-                            self.eval_block(&body.contents, env)?;
+                        ast::IfTail::ElseIf { cond, body, tail: next_tail, .. } => {
+                            if let Some(env) = self.enter_if_cond(cond, env)? {
+                                return Ok(Step::Push(vec![Frame::block(&body.contents, &env)]));
+                            }
+                            tail = next_tail;
                         }
                     }
-                } else {
-                    self.eval_if_tail(tail, env)?;
                 }
             }
             ast::Stmt::Expr { expr, .. } => {
                 self.eval_expr(expr, env)?;
             }
             ast::Stmt::For { name, iterable, body, .. } => {
-                let iter = self.eval_expr(iterable, env)?;
-                let list = match iter {
-                    Value::List(list) => list,
-                    _ => return Err(EvalStop::Error(RuntimeError {
-                        message: format!("cannot iterate over {}", iter.type_name()),
-                        span: Some(iterable.span()),
-                    })),
+                let iter = self.eval_iterator(iterable, env)?;
+                let frame = Frame::For {
+                    name: self.token_source(*name).to_owned(),
+                    iter,
+                    span: iterable.span(),
+                    body: body.contents.stmts.clone(),
+                    env: env.with_fence(),
                 };
-                let values = list.values.borrow().clone();
-                for item in &values {
-                    let iter_env = env.define(self.token_source(*name), item.clone());
-                    self.eval_block(&body.contents, &iter_env)?;
-                }
+                return Ok(Step::Push(vec![frame]));
             }
             ast::Stmt::Return { ret, value, .. } => {
-                if self.call_stack_size == 0 {
-                    return Err(EvalStop::Error(RuntimeError {
-                        message: "cannot use return outside of a function".to_owned(),
-                        span: Some(ret.span),
-                    }));
-                }
+                self.check_in_function("return", ret.span)?;
                 if let Some(value) = value {
                     let value = self.eval_expr(value, env)?;
                     return Err(EvalStop::Return(value));
@@ -403,38 +934,173 @@ impl Evaluator {
                     return Err(EvalStop::Return(Value::Nil));
                 }
             }
+            ast::Stmt::Yield { yield_tok, value, .. } => {
+                self.check_in_function("yield", yield_tok.span)?;
+                let value = match value {
+                    Some(value) => self.eval_expr(value, env)?,
+                    None => Value::Nil,
+                };
+                return Ok(Step::Yield(value));
+            }
             ast::Stmt::Let { name, value, .. } => {
                 let value = self.eval_expr(value, env)?;
-                return Ok(env.define(self.token_source(*name), value));
+                return Ok(Step::Next(env.define(self.token_source(*name), value)));
+            }
+            ast::Stmt::Comment(comment) if self.run_comments => {
+                for elem in &comment.elements {
+                    if let ast::CommentElem::Code { code, .. } = elem {
+                        self.run_snippet(code, env)?;
+                    }
+                }
             }
             ast::Stmt::Comment(_) => {}
             ast::Stmt::FnDef(def) => {
                 let name = self.token_source(def.name);
                 let func = UserFunc {
                     name: name.to_owned(),
+                    source: self.source.clone(),
                     def: def.clone(),
+                    stmt: stmt.clone(),
                     env: env.clone(),
                 };
-                return Ok(env.define(name, Value::UserFunc(Rc::new(func))));
+                return Ok(Step::Next(env.define(name, Value::UserFunc(Rc::new(func)))));
             }
             ast::Stmt::StructDef { name, fns, .. } => {
-                if fns.len() > 0 {
-                    todo!("struct with fns");
-                }
+                let methods = fns
+                    .iter()
+                    .filter_map(|stmt| match &**stmt {
+                        ast::Stmt::FnDef(def) => Some(Rc::new(UserFunc {
+                            name: self.token_source(def.name).to_owned(),
+                            source: self.source.clone(),
+                            def: def.clone(),
+                            stmt: stmt.clone(),
+                            env: env.clone(),
+                        })),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
                 let name = self.token_source(*name);
                 let strukt = Struct {
                     name: name.to_owned(),
                     ctor: None,
+                    fields: intrinsics::assigned_self_fields(&self.source.text, &methods),
+                    methods,
                 };
-                return Ok(env.define(name, Value::Struct(Rc::new(strukt))));
+                return Ok(Step::Next(env.define(name, Value::Struct(Rc::new(strukt)))));
             }
             ast::Stmt::Block(block) => {
-                self.eval_block(&block.contents, env)?;
+                return Ok(Step::Push(vec![Frame::block(&block.contents, env)]));
             }
         }
+        Ok(Step::Next(env.clone()))
+    }
+
+    // Runs frames until the stack is empty or a `yield` is reached. Returns
+    // the yielded value, or `None` if all frames finished. Frames that are
+    // still unfinished after a `yield` are left on the stack, so execution
+    // can be resumed by calling this again.
+    fn run_frames(&mut self, frames: &mut Vec<Frame>) -> Result<Option<Value>, EvalStop> {
+        while let Some(frame) = frames.pop() {
+            match frame {
+                Frame::Block { stmts, next, env } => {
+                    let stmt = match stmts.get(next) {
+                        Some(stmt) => stmt.clone(),
+                        None => continue,
+                    };
+                    match self.exec_statement(&stmt, &env)? {
+                        Step::Next(env) => {
+                            frames.push(Frame::Block { stmts, next: next + 1, env });
+                        }
+                        Step::Push(new_frames) => {
+                            frames.push(Frame::Block { stmts, next: next + 1, env });
+                            frames.extend(new_frames);
+                        }
+                        Step::Yield(value) => {
+                            frames.push(Frame::Block { stmts, next: next + 1, env });
+                            return Ok(Some(value));
+                        }
+                    }
+                }
+                Frame::While { stmt, env } => {
+                    self.check_limit(Limits::count_step, stmt.span())?;
+                    if let ast::Stmt::While { cond, body, .. } = &*stmt {
+                        if let Some(body_env) = self.enter_if_cond(cond, &env)? {
+                            let body = Frame::block(&body.contents, &body_env);
+                            frames.push(Frame::While { stmt, env });
+                            frames.push(body);
+                        }
+                    }
+                }
+                Frame::For { name, mut iter, span, body, env } => {
+                    if let Some(item) = self.next_item(&mut iter, span)? {
+                        let body_env = env.define(&name, item);
+                        frames.push(Frame::For { name, iter, span, body: body.clone(), env });
+                        frames.push(Frame::Block { stmts: body, next: 0, env: body_env });
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // Each snippet gets its own scope, it can see local variables but its
+    // definitions don't leak into the code around it. Snippets run to
+    // completion on their own, so they can't return or yield on behalf of
+    // the function they are in.
+    fn run_snippet(&mut self, code: &ast::NakedBlock, env: &Env) -> Result<(), EvalStop> {
+        let outer_base = self.snippet_base.replace(self.call_stack_size);
+        let mut frames = vec![Frame::block(code, env)];
+        let result = self.run_frames(&mut frames);
+        self.snippet_base = outer_base;
+        result.map(|_| ())
+    }
+
+    fn check_in_function(&self, keyword: &str, span: Span) -> Result<(), RuntimeError> {
+        let message = match self.snippet_base {
+            Some(base) if self.call_stack_size == base => {
+                format!("cannot use {} in a comment snippet", keyword)
+            }
+            None if self.call_stack_size == 0 => {
+                format!("cannot use {} outside of a function", keyword)
+            }
+            _ => return Ok(()),
+        };
+        Err(RuntimeError {
+            message,
+            span: Some(span),
+        })
+    }
+
+    // Runs a statement outside of any function, where a `yield` has no
+    // generator to suspend and is an error.
+    fn eval_statement(&mut self, stmt: &Rc<ast::Stmt>, env: &Env) -> Result<Env, EvalStop> {
+        let yielded = match self.exec_statement(stmt, env)? {
+            Step::Next(env) => return Ok(env),
+            Step::Push(mut frames) => self.run_frames(&mut frames)?.is_some(),
+            Step::Yield(_) => true,
+        };
+        if yielded {
+            return Err(EvalStop::Error(RuntimeError {
+                message: "cannot use yield outside of a function".to_owned(),
+                span: Some(stmt.span()),
+            }));
+        }
         Ok(env.clone())
     }
 
+    // Evaluates loop or if condition, and returns environment for the
+    // body if it should be entered.
+    fn enter_if_cond(&mut self, cond: &ast::IfCond, env: &Env) -> Result<Option<Env>, RuntimeError> {
+        let (is_true, binding) = self.eval_if_cond(cond, env)?;
+        if !is_true {
+            return Ok(None);
+        }
+        match binding {
+            Some((name, value)) => Ok(Some(env.with_fence().define(self.token_source(name), value))),
+            None => Ok(Some(env.clone())),
+        }
+    }
+
     fn eval_if_cond(&mut self, cond: &ast::IfCond, env: &Env) -> Result<(bool, Option<(ast::Token, Value)>), RuntimeError> {
         match cond {
             ast::IfCond::Expr(e) => Ok((self.eval_cond(e, env)?, None)),
@@ -456,31 +1122,13 @@ impl Evaluator {
         }
     }
 
-    fn eval_if_tail(&mut self, tail: &ast::IfTail, env: &Env) -> Result<(), EvalStop> {
-        match tail {
-            ast::IfTail::None => Ok(()),
-            ast::IfTail::Else { body, .. } => self.eval_block(&body.contents, env),
-            ast::IfTail::ElseIf { cond, body, tail, .. } => {
-                let (is_true, binding) = self.eval_if_cond(cond, env)?;
-                if is_true {
-                    match binding {
-                        Some((name, value)) => {
-                            let env = env.with_fence().define(self.token_source(name), value);
-                            self.eval_block(&body.contents, &env)?;
-                        }
-                        None => {
-                            self.eval_block(&body.contents, env)?;
-                        }
-                    }
-                } else {
-                    self.eval_if_tail(tail, env)?;
-                }
-                Ok(())
-            }
-        }
+    fn eval_expr(&mut self, expr: &ast::Expr, env: &Env) -> Result<Value, RuntimeError> {
+        let value = self.eval_expr_unchecked(expr, env)?;
+        self.check_limit(|limits| limits.check_size(&value), expr.span())?;
+        Ok(value)
     }
 
-    fn eval_expr(&mut self, expr: &ast::Expr, env: &Env) -> Result<Value, RuntimeError> {
+    fn eval_expr_unchecked(&mut self, expr: &ast::Expr, env: &Env) -> Result<Value, RuntimeError> {
         match expr {
             ast::Expr::Name { name } => {
                 let text = self.token_source(*name);
@@ -496,80 +1144,13 @@ impl Evaluator {
             ast::Expr::Bool { value, .. } => Ok((*value).into()),
             ast::Expr::Str { value, .. } => Ok(value.as_str().into()),
             ast::Expr::Nil { .. } => Ok(Value::Nil),
-            ast::Expr::SelfExpr { .. } => todo!(),
-            ast::Expr::Call { func, args, .. } => {
-                let func = self.eval_expr(func, env)?;
-                let mut eval_args = || -> Result<Vec<Value>, RuntimeError> {
-                    args.iter()
-                        .map(|arg| self.eval_expr(&arg.item, env))
-                        .collect()
-                };
-                match func {
-                    Value::NativeFunc(f) => {
-                        let args = eval_args()?;
-                        (f.f)(&self.source, &args).map_err(|message| RuntimeError {
-                            message,
-                            span: Some(expr.span()),
-                        })
-                    }
-                    Value::Struct(s) => {
-                        if let Some(ctor) = &s.ctor {
-                            let args = eval_args()?;
-                            (ctor.f)(&self.source, &args).map_err(|message| RuntimeError {
-                                message,
-                                span: Some(expr.span()),
-                            })
-                        } else {
-                            if args.len() > 0 {
-                                return Err(RuntimeError {
-                                    message: format!(
-                                        "{} expects 0 args, got {}",
-                                        s.name,
-                                        args.len(),
-                                    ),
-                                    span: Some(expr.span()),
-                                });
-                            }
-                            Ok(Value::Instance(Rc::new(Instance {
-                                ty: s.clone(),
-                                fields: Default::default(),
-                            })))
-                        }
-                    }
-                    Value::UserFunc(f) => {
-                        let args = eval_args()?;
-                        let mut call_env = f.env.with_fence();
-                        if args.len() != f.def.params.len() {
-                            return Err(RuntimeError {
-                                message: format!(
-                                    "{} expects {} args, got {}",
-                                    f.name,
-                                    f.def.params.len(),
-                                    args.len()
-                                ),
-                                span: Some(expr.span()),
-                            });
-                        }
-                        for (arg, param) in args.into_iter().zip(&f.def.params) {
-                            call_env = call_env.define(self.token_source(param.item), arg);
-                        }
-                        self.call_stack_size += 1;
-                        let result = match self.eval_block(&f.def.body.contents, &call_env) {
-                            Ok(()) => Ok(Value::Nil),
-                            Err(EvalStop::Error(e)) => Err(e),
-                            Err(EvalStop::Return(val)) => Ok(val),
-                        };
-                        self.call_stack_size -= 1;
-                        result
-                    }
-                    other => {
-                        Err(RuntimeError {
-                            message: format!("{} cannot be called", other.type_name()),
-                            span: Some(expr.span()),
-                        })
-                    }
-                }
-            }
+            // `self` is a keyword, so only bound methods can define it
+            ast::Expr::SelfExpr { tok } => env.lookup("self").ok_or_else(|| RuntimeError {
+                message: "`self` can only be used in methods".to_owned(),
+                span: Some(tok.span),
+            }),
+            ast::Expr::Call { .. } |
+            ast::Expr::Field { .. } => Ok(self.eval_chain(expr, env)?.unwrap_or(Value::Nil)),
             ast::Expr::Paren { inner, .. } => self.eval_expr(inner, env),
             ast::Expr::PrefixOp { operator, arg } => {
                 match operator.kind {
@@ -600,6 +1181,12 @@ impl Evaluator {
                             Ok(self.eval_cond(rhs, env)?.into())
                         };
                     }
+                    TokenKind::QuestionQuestion => {
+                        return match self.eval_expr(lhs, env)? {
+                            Value::Nil => self.eval_expr(rhs, env),
+                            value => Ok(value),
+                        };
+                    }
                     _ => {}
                 }
                 let lhs = self.eval_expr(lhs, env)?;
@@ -622,21 +1209,6 @@ impl Evaluator {
                     span: Some(expr.span()),
                 })
             }
-            ast::Expr::Field { obj, field, .. } => {
-                let obj = self.eval_expr(obj, env)?;
-                let field_name = self.token_source(*field);
-                match obj.lookup_field(field_name) {
-                    Some(value) => Ok(value),
-                    None => Err(RuntimeError {
-                        message: format!(
-                            "{} does not have field `{}`",
-                            obj.type_name(),
-                            field_name,
-                        ),
-                        span: Some(field.span),
-                    }),
-                }
-            }
             ast::Expr::AssignVar { name, value, .. } => {
                 let value = self.eval_expr(value, env)?;
                 match env.set(self.token_source(*name), value.clone()) {
@@ -647,6 +1219,21 @@ impl Evaluator {
                     }),
                 }
             }
+            ast::Expr::ListComp(comp) => {
+                let values = self.eval_iterable(&comp.iterable, env)?;
+                let comp_env = env.with_fence();
+                let mut result = Vec::new();
+                for value in values {
+                    let item_env = comp_env.define(self.token_source(comp.name), value);
+                    if let Some(filter) = &comp.filter {
+                        if !self.eval_cond(filter, &item_env)? {
+                            continue;
+                        }
+                    }
+                    result.push(self.eval_expr(&comp.item, &item_env)?);
+                }
+                Ok(Value::List(Rc::new(List::new(result))))
+            }
             ast::Expr::AssignField { obj, field, value, .. } => {
                 let obj = self.eval_expr(obj, env)?;
                 let value = self.eval_expr(value, env)?;
@@ -661,6 +1248,194 @@ impl Evaluator {
         }
     }
 
+    // Evaluates a chain of field accesses and calls. Returns `None` if the
+    // chain was short-circuited by `?.` on a nil value.
+    fn eval_chain(&mut self, expr: &ast::Expr, env: &Env) -> Result<Option<Value>, RuntimeError> {
+        match expr {
+            ast::Expr::Call { func, args, .. } => {
+                match self.eval_chain(func, env)? {
+                    Some(func) => self.eval_call(func, args, expr, env).map(Some),
+                    None => Ok(None),
+                }
+            }
+            ast::Expr::Field { obj, dot, field } => {
+                let obj = match self.eval_chain(obj, env)? {
+                    Some(Value::Nil) if dot.kind == TokenKind::QuestionDot => return Ok(None),
+                    Some(obj) => obj,
+                    None => return Ok(None),
+                };
+                let field_name = self.token_source(*field);
+                match obj.lookup_field(field_name) {
+                    Some(value) => Ok(Some(value)),
+                    None => Err(RuntimeError {
+                        message: format!(
+                            "{} does not have field `{}`",
+                            obj.type_name(),
+                            field_name,
+                        ),
+                        span: Some(field.span),
+                    }),
+                }
+            }
+            _ => self.eval_expr(expr, env).map(Some),
+        }
+    }
+
+    fn eval_call(
+        &mut self,
+        func: Value,
+        args: &ast::CommaList<Rc<ast::Expr>>,
+        expr: &ast::Expr,
+        env: &Env,
+    ) -> Result<Value, RuntimeError> {
+        let mut eval_args = || -> Result<Vec<Value>, RuntimeError> {
+            args.iter()
+                .map(|arg| self.eval_expr(&arg.item, env))
+                .collect()
+        };
+        match func {
+            Value::NativeFunc(f) => {
+                let args = eval_args()?;
+                (f.f)(self, &args).map_err(|message| RuntimeError {
+                    message,
+                    span: Some(expr.span()),
+                })
+            }
+            Value::Struct(s) => {
+                if let Some(ctor) = &s.ctor {
+                    let args = eval_args()?;
+                    (ctor.f)(self, &args).map_err(|message| RuntimeError {
+                        message,
+                        span: Some(expr.span()),
+                    })
+                } else {
+                    if args.len() > 0 {
+                        return Err(RuntimeError {
+                            message: format!(
+                                "{} expects 0 args, got {}",
+                                s.name,
+                                args.len(),
+                            ),
+                            span: Some(expr.span()),
+                        });
+                    }
+                    Ok(Value::Instance(Rc::new(Instance {
+                        ty: s.clone(),
+                        fields: Default::default(),
+                    })))
+                }
+            }
+            Value::UserFunc(f) => {
+                let args = eval_args()?;
+                let mut call_env = f.env.with_fence();
+                if args.len() != f.def.params.len() {
+                    return Err(RuntimeError {
+                        message: format!(
+                            "{} expects {} args, got {}",
+                            f.name,
+                            f.def.params.len(),
+                            args.len()
+                        ),
+                        span: Some(expr.span()),
+                    });
+                }
+                for (arg, param) in args.into_iter().zip(&f.def.params) {
+                    let name = &f.source.text[param.item.span.source_range()];
+                    call_env = call_env.define(name, arg);
+                }
+                let mut frames = vec![Frame::block(&f.def.body.contents, &call_env)];
+                if f.def.is_generator {
+                    return Ok(Value::Generator(Rc::new(Generator {
+                        name: f.name.clone(),
+                        source: f.source.clone(),
+                        state: RefCell::new(GeneratorState::Suspended(frames)),
+                    })));
+                }
+                self.enter_call(expr.span())?;
+                let result = self.with_source(&f.source, |this| this.run_frames(&mut frames));
+                self.leave_call();
+                match result {
+                    Ok(_) => Ok(Value::Nil),
+                    Err(EvalStop::Error(e)) => Err(self.foreign_error(&f.source, &f.name, e, expr.span())),
+                    Err(EvalStop::Return(val)) => Ok(val),
+                }
+            }
+            other => {
+                Err(RuntimeError {
+                    message: format!("{} cannot be called", other.type_name()),
+                    span: Some(expr.span()),
+                })
+            }
+        }
+    }
+
+    fn eval_iterator(&mut self, iterable: &ast::Expr, env: &Env) -> Result<Iter, RuntimeError> {
+        match self.eval_expr(iterable, env)? {
+            Value::List(list) => Ok(Iter::Values(list.values.borrow().clone().into_iter())),
+            Value::Set(set) => Ok(Iter::Values(set.values().into_iter())),
+            Value::Generator(gen) => Ok(Iter::Generator(gen)),
+            other => Err(RuntimeError {
+                message: format!("cannot iterate over {}", other.type_name()),
+                span: Some(iterable.span()),
+            }),
+        }
+    }
+
+    fn eval_iterable(&mut self, iterable: &ast::Expr, env: &Env) -> Result<Vec<Value>, RuntimeError> {
+        let mut iter = self.eval_iterator(iterable, env)?;
+        let mut values = Vec::new();
+        while let Some(value) = self.next_item(&mut iter, iterable.span())? {
+            values.push(value);
+        }
+        Ok(values)
+    }
+
+    fn next_item(&mut self, iter: &mut Iter, span: Span) -> Result<Option<Value>, RuntimeError> {
+        match iter {
+            Iter::Values(values) => Ok(values.next()),
+            Iter::Generator(gen) => self.resume_generator(gen, span),
+        }
+    }
+
+    // Runs generator until its next `yield`. Returns `None` once the
+    // generator has finished.
+    fn resume_generator(&mut self, gen: &Generator, span: Span) -> Result<Option<Value>, RuntimeError> {
+        let state = std::mem::replace(&mut *gen.state.borrow_mut(), GeneratorState::Running);
+        let mut frames = match state {
+            GeneratorState::Suspended(frames) => frames,
+            GeneratorState::Running => {
+                return Err(RuntimeError {
+                    message: format!("generator {} is already running", gen.name),
+                    span: Some(span),
+                });
+            }
+            GeneratorState::Finished => {
+                *gen.state.borrow_mut() = GeneratorState::Finished;
+                return Ok(None);
+            }
+        };
+        if let Err(e) = self.enter_call(span) {
+            *gen.state.borrow_mut() = GeneratorState::Suspended(frames);
+            return Err(e);
+        }
+        let result = self.with_source(&gen.source, |this| this.run_frames(&mut frames));
+        self.leave_call();
+        match result {
+            Ok(Some(value)) => {
+                *gen.state.borrow_mut() = GeneratorState::Suspended(frames);
+                Ok(Some(value))
+            }
+            Ok(None) | Err(EvalStop::Return(_)) => {
+                *gen.state.borrow_mut() = GeneratorState::Finished;
+                Ok(None)
+            }
+            Err(EvalStop::Error(e)) => {
+                *gen.state.borrow_mut() = GeneratorState::Finished;
+                Err(self.foreign_error(&gen.source, &gen.name, e, span))
+            }
+        }
+    }
+
     fn eval_cond(&mut self, cond: &ast::Expr, env: &Env) -> Result<bool, RuntimeError> {
         match self.eval_expr(cond, env)? {
             Value::Bool(b) => Ok(b),
@@ -671,12 +1446,34 @@ impl Evaluator {
         }
     }
 
-    fn eval_block(&mut self, block: &ast::NakedBlock, env: &Env) -> Result<(), EvalStop> {
-        let mut env = env.with_fence();
-        for stmt in &block.stmts {
-            env = self.eval_statement(stmt, &env)?;
+    // Runs `f` with `source` used for looking up text of tokens. Code can
+    // come from other sources than the program itself, e.g. from `parse`.
+    fn with_source<T>(&mut self, source: &Rc<Source>, f: impl FnOnce(&mut Self) -> T) -> T {
+        let prev = std::mem::replace(&mut self.source, source.clone());
+        let result = f(self);
+        self.source = prev;
+        result
+    }
+
+    // Errors from code in a different source can't point into it, so they
+    // are reported at `span` instead, mentioning the original line.
+    fn foreign_error(&self, source: &Rc<Source>, name: &str, e: RuntimeError, span: Span) -> RuntimeError {
+        if Rc::ptr_eq(source, &self.source) {
+            return e;
+        }
+        let message = match e.span {
+            Some(inner) => format!(
+                "{} (in {} at line {} of its source)",
+                e.message,
+                name,
+                source.span_start_line(inner),
+            ),
+            None => e.message,
+        };
+        RuntimeError {
+            message,
+            span: Some(span),
         }
-        Ok(())
     }
 
     fn token_source(&self, token: ast::Token) -> &str {
@@ -684,32 +1481,19 @@ impl Evaluator {
     }
 
     fn value_type(&self, value: &Value) -> Rc<Struct> {
-        match value {
-            Value::Nil => self.builtins.nil.clone(),
-            Value::Int(_) => self.builtins.int.clone(),
-            Value::Bool(_) => self.builtins.bool.clone(),
-            Value::Str(_) => self.builtins.str.clone(),
-            Value::NativeFunc(_) => self.builtins.func.clone(),
-            Value::Struct(_) => self.builtins.strukt.clone(),
-            Value::Instance(i) => i.ty.clone(),
-            Value::List(_) => self.builtins.list.clone(),
-            Value::UserFunc(_) => self.builtins.func.clone(),
-            Value::Stmt(s) => if s.is_code() {
-                self.builtins.code.clone()
-            } else {
-                self.builtins.comment.clone()
-            },
-            Value::Interpreter(_) => self.builtins.interpreter.clone(),
-        }
+        self.builtins.value_type(value)
     }
 
     pub(crate) fn run_program(&mut self, program: &ast::Program) -> Result<(), RuntimeError> {
         let mut env = self.globals.clone();
-        for stmt in &program.code.stmts {
+        for stmt in program.code.stmts.iter() {
             match self.eval_statement(stmt, &env) {
                 Ok(e) => env = e,
                 Err(EvalStop::Error(e)) => return Err(e),
-                Err(EvalStop::Return(_)) => panic!("return outside of function"),
+                Err(EvalStop::Return(_)) => return Err(RuntimeError {
+                    message: "cannot use return outside of a function".to_owned(),
+                    span: Some(stmt.span()),
+                }),
             }
         }
         Ok(())
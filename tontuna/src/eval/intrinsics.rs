@@ -1,8 +1,9 @@
 use std::io::Write;
 use std::rc::Rc;
 use std::cmp::Ordering;
-use crate::{ast, Source};
+use crate::{ast, Source, Span};
 use super::Value;
+use super::types::{CommentElement, ElementKind};
 
 fn compare(lhs: &Value, rhs: &Value) -> Option<Ordering> {
     match (lhs, rhs) {
@@ -14,19 +15,37 @@ fn compare(lhs: &Value, rhs: &Value) -> Option<Ordering> {
         (Value::Bool(_), _) | (_, Value::Bool(_)) => None,
         (Value::Str(a), Value::Str(b)) => Some(a.cmp(b)),
         (Value::Str(_), _) | (_, Value::Str(_)) => None,
-        (Value::NativeFunc(a), Value::NativeFunc(b)) => Rc::ptr_eq(a, b).then(|| Ordering::Equal),
+        (Value::NativeFunc(a), Value::NativeFunc(b)) => Rc::ptr_eq(a, b).then_some(Ordering::Equal),
         (Value::NativeFunc(_), _) | (_, Value::NativeFunc(_)) => None,
-        (Value::Struct(a), Value::Struct(b)) => Rc::ptr_eq(a, b).then(|| Ordering::Equal),
+        (Value::Struct(a), Value::Struct(b)) => Rc::ptr_eq(a, b).then_some(Ordering::Equal),
         (Value::Struct(_), _) | (_, Value::Struct(_)) => None,
-        (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(a, b).then(|| Ordering::Equal),
+        (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(a, b).then_some(Ordering::Equal),
         (Value::Instance(_), _) | (_, Value::Instance(_)) => None,
-        (Value::List(a), Value::List(b)) => Rc::ptr_eq(a, b).then(|| Ordering::Equal),
+        (Value::List(a), Value::List(b)) => Rc::ptr_eq(a, b).then_some(Ordering::Equal),
         (Value::List(_), _) | (_, Value::List(_)) => None,
-        (Value::UserFunc(a), Value::UserFunc(b)) => Rc::ptr_eq(a, b).then(|| Ordering::Equal),
+        (Value::Set(a), Value::Set(b)) => Rc::ptr_eq(a, b).then_some(Ordering::Equal),
+        (Value::Set(_), _) | (_, Value::Set(_)) => None,
+        (Value::UserFunc(a), Value::UserFunc(b)) => Rc::ptr_eq(a, b).then_some(Ordering::Equal),
         (Value::UserFunc(_), _) | (_, Value::UserFunc(_)) => None,
-        (Value::Stmt(a), Value::Stmt(b)) => Rc::ptr_eq(a, b).then(|| Ordering::Equal),
+        (Value::Stmt(a), Value::Stmt(b)) => Rc::ptr_eq(&a.ast, &b.ast).then_some(Ordering::Equal),
         (Value::Stmt(_), _) | (_, Value::Stmt(_)) => None,
-        (Value::Interpreter(a), Value::Interpreter(b)) => Rc::ptr_eq(a, b).then(|| Ordering::Equal),
+        (Value::Interpreter(a), Value::Interpreter(b)) => Rc::ptr_eq(a, b).then_some(Ordering::Equal),
+        (Value::Interpreter(_), _) | (_, Value::Interpreter(_)) => None,
+        (Value::Generator(a), Value::Generator(b)) => Rc::ptr_eq(a, b).then_some(Ordering::Equal),
+        (Value::Generator(_), _) | (_, Value::Generator(_)) => None,
+        (Value::Span(a), Value::Span(b)) => {
+            (Rc::ptr_eq(&a.source, &b.source) && a.span == b.span).then_some(Ordering::Equal)
+        }
+        (Value::Span(_), _) | (_, Value::Span(_)) => None,
+        (Value::CommentElement(a), Value::CommentElement(b)) => Rc::ptr_eq(a, b).then_some(Ordering::Equal),
+        (Value::CommentElement(_), _) | (_, Value::CommentElement(_)) => None,
+        (Value::Error(a), Value::Error(b)) => Rc::ptr_eq(a, b).then_some(Ordering::Equal),
+        (Value::Error(_), _) | (_, Value::Error(_)) => None,
+        (Value::Result(a), Value::Result(b)) => Rc::ptr_eq(a, b).then_some(Ordering::Equal),
+        (Value::Result(_), _) | (_, Value::Result(_)) => None,
+        (Value::Expr(a), Value::Expr(b)) => {
+            (Rc::ptr_eq(&a.source, &b.source) && a.ast.span() == b.ast.span()).then_some(Ordering::Equal)
+        }
     }
 }
 
@@ -169,6 +188,24 @@ pub(super) fn string_get(s: &Value, idx: &Value) -> Result<Value, String> {
     }
 }
 
+pub(super) fn string_chars(s: &Value, args: &[Value]) -> Result<Value, String> {
+    let s = match s {
+        Value::Str(s) => s,
+        other => return Err(format!(
+            "first argument must be Str but was {}",
+            other.type_name(),
+        )),
+    };
+    if !args.is_empty() {
+        return Err(format!("chars expects 0 arguments, got {}", args.len()));
+    }
+    let chars = s.chars
+        .iter()
+        .map(|&c| super::Str { chars: vec![c] }.into())
+        .collect();
+    Ok(Value::List(Rc::new(super::List::new(chars))))
+}
+
 pub(super) fn panic(args: &[Value]) -> String {
     if args.len() == 0 {
         return "panic".to_owned();
@@ -218,6 +255,199 @@ pub(super) fn list_ctor(values: &[Value]) -> Value {
     Value::List(Rc::new(super::List::new(values.to_vec())))
 }
 
+pub(super) fn set_ctor(values: &[Value]) -> Result<Value, String> {
+    let keys = values
+        .iter()
+        .map(super::SetKey::from_value)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Value::Set(Rc::new(super::Set::new(keys))))
+}
+
+fn as_set(value: &Value, position: &str) -> Result<Rc<super::Set>, String> {
+    match value {
+        Value::Set(s) => Ok(s.clone()),
+        other => Err(format!(
+            "{} argument must be Set but was {}",
+            position,
+            other.type_name(),
+        )),
+    }
+}
+
+pub(super) fn set_add(s: &Value, val: &Value) -> Result<Value, String> {
+    let s = as_set(s, "first")?;
+    s.insert(super::SetKey::from_value(val)?);
+    Ok(Value::Nil)
+}
+
+pub(super) fn set_has(s: &Value, val: &Value) -> Result<Value, String> {
+    let s = as_set(s, "first")?;
+    Ok(s.contains(&super::SetKey::from_value(val)?).into())
+}
+
+pub(super) fn set_remove(s: &Value, val: &Value) -> Result<Value, String> {
+    let s = as_set(s, "first")?;
+    Ok(s.remove(&super::SetKey::from_value(val)?).into())
+}
+
+pub(super) fn set_union(s: &Value, other: &Value) -> Result<Value, String> {
+    let s = as_set(s, "first")?;
+    let other = as_set(other, "second")?;
+    let keys = s.keys().into_iter().chain(other.keys());
+    Ok(Value::Set(Rc::new(super::Set::new(keys))))
+}
+
+pub(super) fn set_intersection(s: &Value, other: &Value) -> Result<Value, String> {
+    let s = as_set(s, "first")?;
+    let other = as_set(other, "second")?;
+    let keys = s.keys().into_iter().filter(|k| other.contains(k));
+    Ok(Value::Set(Rc::new(super::Set::new(keys))))
+}
+
+pub(super) fn set_difference(s: &Value, other: &Value) -> Result<Value, String> {
+    let s = as_set(s, "first")?;
+    let other = as_set(other, "second")?;
+    let keys = s.keys().into_iter().filter(|k| !other.contains(k));
+    Ok(Value::Set(Rc::new(super::Set::new(keys))))
+}
+
+fn field_name(name: &Value) -> Result<&super::Str, String> {
+    match name {
+        Value::Str(s) => Ok(s),
+        other => Err(format!(
+            "second argument must be Str but was {}",
+            other.type_name(),
+        )),
+    }
+}
+
+pub(super) fn get_field(obj: &Value, name: &Value) -> Result<Value, String> {
+    let name = field_name(name)?.to_string();
+    obj.lookup_field(&name).ok_or_else(|| format!(
+        "{} does not have field `{}`",
+        obj.type_name(),
+        name,
+    ))
+}
+
+pub(super) fn set_field(obj: &Value, name: &Value, value: &Value) -> Result<Value, String> {
+    let name = field_name(name)?.to_string();
+    obj.set_field(&name, value.clone())?;
+    Ok(value.clone())
+}
+
+pub(super) fn has_field(obj: &Value, name: &Value) -> Result<Value, String> {
+    let name = field_name(name)?.to_string();
+    Ok(obj.lookup_field(&name).is_some().into())
+}
+
+// Lists fields of an instance, sorted by name.
+pub(super) fn fields(obj: &Value) -> Result<Value, String> {
+    let i = match obj {
+        Value::Instance(i) => i,
+        other => return Err(format!(
+            "first argument must be an instance but was {}",
+            other.type_name(),
+        )),
+    };
+    let mut names = i.fields.borrow().keys().cloned().collect::<Vec<_>>();
+    names.sort();
+    Ok(Value::List(Rc::new(super::List::new(
+        names.iter().map(|n| Value::from(n.as_str())).collect(),
+    ))))
+}
+
+// Names of the fields that `methods` assign to `self`, sorted the same way
+// as `fields` of instances.
+pub(super) fn assigned_self_fields(source: &str, methods: &[Rc<super::UserFunc>]) -> Vec<String> {
+    fn visit_cond<'a>(cond: &'a ast::IfCond, exprs: &mut Vec<&'a Rc<ast::Expr>>) {
+        match cond {
+            ast::IfCond::Expr(e) => exprs.push(e),
+            ast::IfCond::TypeTest { ty, value, .. } => {
+                exprs.push(ty);
+                exprs.push(value);
+            }
+        }
+    }
+    fn visit_block<'a>(block: &'a ast::NakedBlock, exprs: &mut Vec<&'a Rc<ast::Expr>>) {
+        for stmt in block.stmts.iter() {
+            match &**stmt {
+                ast::Stmt::If { cond, body, tail, .. } => {
+                    visit_cond(cond, exprs);
+                    visit_block(&body.contents, exprs);
+                    let mut tail: &ast::IfTail = tail;
+                    while let ast::IfTail::ElseIf { cond, body, tail: next_tail, .. } = tail {
+                        visit_cond(cond, exprs);
+                        visit_block(&body.contents, exprs);
+                        tail = next_tail;
+                    }
+                    if let ast::IfTail::Else { body, .. } = tail {
+                        visit_block(&body.contents, exprs);
+                    }
+                }
+                ast::Stmt::While { cond, body, .. } => {
+                    visit_cond(cond, exprs);
+                    visit_block(&body.contents, exprs);
+                }
+                ast::Stmt::For { iterable, body, .. } => {
+                    exprs.push(iterable);
+                    visit_block(&body.contents, exprs);
+                }
+                ast::Stmt::Expr { expr: value, .. } |
+                ast::Stmt::Let { value, .. } => exprs.push(value),
+                ast::Stmt::Return { value, .. } |
+                ast::Stmt::Yield { value, .. } => exprs.extend(value.as_ref()),
+                ast::Stmt::Block(body) => visit_block(&body.contents, exprs),
+                // `self` of nested definitions is a different value, snippets
+                // in comments don't run with the method
+                ast::Stmt::FnDef(_) |
+                ast::Stmt::StructDef { .. } |
+                ast::Stmt::Comment(_) => {}
+            }
+        }
+    }
+    let mut exprs = Vec::new();
+    for method in methods {
+        visit_block(&method.def.body.contents, &mut exprs);
+    }
+    let mut fields = std::collections::BTreeSet::new();
+    while let Some(expr) = exprs.pop() {
+        match &**expr {
+            ast::Expr::Name { .. } |
+            ast::Expr::Number { .. } |
+            ast::Expr::Bool { .. } |
+            ast::Expr::Str { .. } |
+            ast::Expr::Nil { .. } |
+            ast::Expr::SelfExpr { .. } => {}
+            ast::Expr::Call { func, args, .. } => {
+                exprs.push(func);
+                exprs.extend(args.iter().map(|a| &a.item));
+            }
+            ast::Expr::Paren { inner: e, .. } |
+            ast::Expr::PrefixOp { arg: e, .. } |
+            ast::Expr::Field { obj: e, .. } |
+            ast::Expr::AssignVar { value: e, .. } => exprs.push(e),
+            ast::Expr::BinOp { lhs, rhs, .. } => {
+                exprs.push(lhs);
+                exprs.push(rhs);
+            }
+            ast::Expr::AssignField { obj, field, value, .. } => {
+                if let ast::Expr::SelfExpr { .. } = **obj {
+                    fields.insert(source[field.span.source_range()].to_owned());
+                }
+                exprs.push(obj);
+                exprs.push(value);
+            }
+            ast::Expr::ListComp(c) => {
+                exprs.push(&c.item);
+                exprs.push(&c.iterable);
+                exprs.extend(c.filter.as_ref());
+            }
+        }
+    }
+    fields.into_iter().collect()
+}
+
 pub(super) fn str_ctor(values: &[Value]) -> Value {
     let mut result = String::new();
     for value in values {
@@ -238,9 +468,9 @@ pub(super) fn invalid_ctor() -> String {
 
 pub(super) fn stmt_children(stmt: &super::Stmt) -> Value {
     let children: Vec<Rc<ast::Stmt>> = match &*stmt.ast {
-        ast::Stmt::While { body, .. } => body.contents.stmts.clone(),
+        ast::Stmt::While { body, .. } => body.contents.stmts.to_vec(),
         ast::Stmt::If { body, tail, .. } => {
-            let mut children = body.contents.stmts.clone();
+            let mut children = body.contents.stmts.to_vec();
             let mut tail: &ast::IfTail = tail;
             loop {
                 match tail {
@@ -258,14 +488,15 @@ pub(super) fn stmt_children(stmt: &super::Stmt) -> Value {
             children
         }
         ast::Stmt::Expr { .. } => Vec::new(),
-        ast::Stmt::For { body, .. } => body.contents.stmts.clone(),
+        ast::Stmt::For { body, .. } => body.contents.stmts.to_vec(),
         ast::Stmt::Return { .. } => Vec::new(),
+        ast::Stmt::Yield { .. } => Vec::new(),
         ast::Stmt::Let { .. } => Vec::new(),
         ast::Stmt::Comment(c) => {
             let mut children = Vec::new();
             for elem in &c.elements {
                 match elem {
-                    ast::CommentElem::Text(_) => {},
+                    ast::CommentElem::Text { .. } => {},
                     ast::CommentElem::Code { code, .. } => {
                         children.extend(code.stmts.iter().cloned());
                     }
@@ -273,52 +504,686 @@ pub(super) fn stmt_children(stmt: &super::Stmt) -> Value {
             }
             children
         }
-        ast::Stmt::FnDef(f) => f.body.contents.stmts.clone(),
+        ast::Stmt::FnDef(f) => f.body.contents.stmts.to_vec(),
         ast::Stmt::StructDef { .. } => Vec::new(),
-        ast::Stmt::Block(b) => b.contents.stmts.clone(),
+        ast::Stmt::Block(b) => b.contents.stmts.to_vec(),
     };
+    stmt_list(&stmt.source, children)
+}
+
+pub(super) fn interpreter_ctor(parent: &super::Evaluator, options: &[Value]) -> Result<Value, String> {
+    let mut forward_output = false;
+    let mut copy_globals = false;
+    for option in options {
+        match option {
+            Value::Str(s) => match s.to_string().as_str() {
+                "forward_output" => forward_output = true,
+                "copy_globals" => copy_globals = true,
+                other => return Err(format!("unknown Interpreter option `{}`", other)),
+            },
+            other => return Err(format!(
+                "Interpreter options must be Str but got {}",
+                other.type_name(),
+            )),
+        }
+    }
+    Ok(super::Interpreter::new(parent, forward_output, copy_globals))
+}
+
+fn as_interpreter(inp: &Value) -> Result<&Rc<super::Interpreter>, String> {
+    match inp {
+        Value::Interpreter(inp) => Ok(inp),
+        other => Err(format!(
+            "first argument must be Interpreter but was {}",
+            other.type_name(),
+        )),
+    }
+}
+
+fn variable_name(name: &Value) -> Result<String, String> {
+    match name {
+        Value::Str(s) => Ok(s.to_string()),
+        other => Err(format!(
+            "variable name must be Str but was {}",
+            other.type_name(),
+        )),
+    }
+}
+
+pub(super) fn interpreter_get(inp: &Value, name: &Value) -> Result<Value, String> {
+    let i = as_interpreter(inp)?;
+    let name = variable_name(name)?;
+    let env = i.env.borrow();
+    env.lookup(&name).ok_or_else(|| format!("undefined variable: {}", name))
+}
+
+pub(super) fn interpreter_set(inp: &Value, name: &Value, value: &Value) -> Result<Value, String> {
+    let i = as_interpreter(inp)?;
+    let name = variable_name(name)?;
+    let env = i.env.borrow().define(&name, value.clone());
+    *i.env.borrow_mut() = env;
+    Ok(Value::Nil)
+}
+
+pub(super) fn interpreter_names(inp: &Value, values: &[Value]) -> Result<Value, String> {
+    let i = as_interpreter(inp)?;
+    if !values.is_empty() {
+        return Err(format!("names expects 0 arguments, got {}", values.len()));
+    }
+    let names = i.env.borrow().names();
+    Ok(Value::List(Rc::new(super::List::new(
+        names.iter().map(|name| Value::from(name.as_str())).collect(),
+    ))))
+}
+
+pub(super) fn interpreter_set_limit(inp: &Value, name: &Value, limit: &Value) -> Result<Value, String> {
+    let i = as_interpreter(inp)?;
+    let limit = match limit {
+        Value::Int(n) if *n >= 0 => Some(*n as u64),
+        Value::Nil => None,
+        other => return Err(format!(
+            "limit must be a non-negative Int or nil but was {}",
+            other.stringify(),
+        )),
+    };
+    let eval = match i.eval.try_borrow() {
+        Ok(eval) => eval,
+        Err(_) => return Err("Interpreter is already running".to_owned()),
+    };
+    let mut limits = eval.limits.borrow_mut();
+    match name {
+        Value::Str(s) => match s.to_string().as_str() {
+            "steps" => limits.max_steps = limit,
+            "call_depth" => limits.max_call_depth = limit,
+            "output" => limits.max_output = limit,
+            "size" => limits.max_size = limit,
+            other => return Err(format!("unknown Interpreter limit `{}`", other)),
+        },
+        other => return Err(format!(
+            "limit name must be Str but was {}",
+            other.type_name(),
+        )),
+    }
+    Ok(Value::Nil)
+}
+
+pub(super) fn interpreter_fork(inp: &Value, values: &[Value]) -> Result<Value, String> {
+    let i = as_interpreter(inp)?;
+    if !values.is_empty() {
+        return Err(format!("fork expects 0 arguments, got {}", values.len()));
+    }
+    i.fork()
+}
+
+pub(super) fn interpreter_take_output(inp: &Value, values: &[Value]) -> Result<Value, String> {
+    let i = as_interpreter(inp)?;
+    if !values.is_empty() {
+        return Err(format!("take_output expects 0 arguments, got {}", values.len()));
+    }
+    let output = match &i.output {
+        super::types::InterpreterOutput::Captured(text) => std::mem::take(&mut *text.borrow_mut()),
+        super::types::InterpreterOutput::Forwarded(_) => String::new(),
+    };
+    Ok(Value::from(output.as_str()))
+}
+
+pub(super) fn interpreter_run(inp: &Value, code: &Value) -> Result<Value, String> {
+    let i = as_interpreter(inp)?;
+    let stmts = match code {
+        Value::Stmt(stmt) => vec![stmt.clone()],
+        Value::List(list) => list.values
+            .borrow()
+            .iter()
+            .map(|item| match item {
+                Value::Stmt(stmt) => Ok(stmt.clone()),
+                other => Err(format!(
+                    "second argument must be a list of Comment or Code but it contains {}",
+                    other.type_name(),
+                )),
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        Value::CommentElement(e) => match &e.kind {
+            ElementKind::Snippet { code, .. } => code.stmts
+                .iter()
+                .map(|stmt| Rc::new(super::Stmt {
+                    source: e.source.clone(),
+                    ast: stmt.clone(),
+                }))
+                .collect(),
+            ElementKind::Text { .. } => return Err("second argument must be a snippet, not text".to_owned()),
+        },
+        other => return Err(format!(
+            "second argument must be Comment, Code, a snippet or a list of them but was {}",
+            other.type_name(),
+        )),
+    };
+    let mut eval = match i.eval.try_borrow_mut() {
+        Ok(eval) => eval,
+        Err(_) => return Err("Interpreter is already running".to_owned()),
+    };
+    eval.limits.borrow_mut().reset_usage();
+    // value of a trailing expression statement
+    let mut value = Value::Nil;
+    for stmt in &stmts {
+        let env = i.env.borrow().clone();
+        let result = eval.with_source(&stmt.source, |eval| match &*stmt.ast {
+            ast::Stmt::Expr { expr, .. } => match eval.eval_expr(expr, &env) {
+                Ok(value) => Ok((env, value)),
+                Err(e) => Err(super::EvalStop::Error(e)),
+            },
+            _ => eval.eval_statement(&stmt.ast, &env).map(|env| (env, Value::Nil)),
+        });
+        let error = match result {
+            Ok((new_env, new_value)) => {
+                *i.env.borrow_mut() = new_env;
+                value = new_value;
+                continue;
+            }
+            Err(super::EvalStop::Return(_)) => super::RuntimeError {
+                message: "cannot use return outside of a function".to_owned(),
+                span: Some(stmt.ast.span()),
+            },
+            Err(super::EvalStop::Error(err)) => err,
+        };
+        let kind = if eval.limits.borrow().exceeded { "limit" } else { "runtime" };
+        return Ok(Value::Error(Rc::new(super::types::Error {
+            kind,
+            message: error.message,
+            location: error.span.map(|span| (stmt.source.clone(), span)),
+            code: Some(stmt.clone()),
+        })));
+    }
+    Ok(Value::Result(Rc::new(super::types::RunResult { value })))
+}
+
+pub(super) fn parse(text: &Value) -> Result<Value, String> {
+    let text = match text {
+        Value::Str(s) => s.to_string(),
+        other => return Err(format!(
+            "first argument must be Str but was {}",
+            other.type_name(),
+        )),
+    };
+    match crate::parser::parse(&text) {
+        Ok(program) => {
+            let source = Rc::new(Source::with_program(text, &program));
+            Ok(stmt_list(&source, program.code.stmts.iter().cloned()))
+        }
+        Err(e) => Ok(Value::Error(Rc::new(super::types::Error {
+            kind: "parse",
+            message: e.message,
+            location: Some((Rc::new(Source::new(text)), e.span)),
+            code: None,
+        }))),
+    }
+}
+
+pub(super) fn stmt_list(source: &Rc<Source>, stmts: impl IntoIterator<Item = Rc<ast::Stmt>>) -> Value {
     Value::List(Rc::new(super::List::new(
-        children
+        stmts
             .into_iter()
             .map(|s| Value::Stmt(Rc::new(super::Stmt {
-                source: stmt.source.clone(),
-                ast: s.clone(),
+                source: source.clone(),
+                ast: s,
             })))
             .collect(),
     )))
 }
 
-pub(super) fn interpreter_run(source: &Source, inp: &Value, stmt: &Value) -> Result<Value, String> {
-    let i = match inp {
-        Value::Interpreter(inp) => inp,
+pub(super) fn stmt_kind(stmt: &ast::Stmt) -> &'static str {
+    match stmt {
+        ast::Stmt::If { .. } => "if",
+        ast::Stmt::While { .. } => "while",
+        ast::Stmt::Expr { .. } => "expr",
+        ast::Stmt::For { .. } => "for",
+        ast::Stmt::Return { .. } => "return",
+        ast::Stmt::Yield { .. } => "yield",
+        ast::Stmt::Let { .. } => "let",
+        ast::Stmt::Comment(_) => "comment",
+        ast::Stmt::FnDef(_) => "fn",
+        ast::Stmt::StructDef { .. } => "struct",
+        ast::Stmt::Block(_) => "block",
+    }
+}
+
+pub(super) fn stmt_name(stmt: &super::Stmt) -> Value {
+    let name = match &*stmt.ast {
+        ast::Stmt::FnDef(f) => f.name,
+        ast::Stmt::Let { name, .. } |
+        ast::Stmt::StructDef { name, .. } => *name,
+        _ => return Value::Nil,
+    };
+    Value::from(&stmt.source.text[name.span.source_range()])
+}
+
+pub(super) fn stmt_params(stmt: &super::Stmt) -> Value {
+    match &*stmt.ast {
+        ast::Stmt::FnDef(f) => Value::List(Rc::new(super::List::new(
+            f.params
+                .iter()
+                .map(|p| Value::from(&stmt.source.text[p.item.span.source_range()]))
+                .collect(),
+        ))),
+        _ => Value::Nil,
+    }
+}
+
+pub(super) fn stmt_body(stmt: &super::Stmt) -> Value {
+    let body = match &*stmt.ast {
+        ast::Stmt::FnDef(f) => &f.body.contents,
+        ast::Stmt::While { body, .. } |
+        ast::Stmt::If { body, .. } |
+        ast::Stmt::For { body, .. } => &body.contents,
+        ast::Stmt::Block(b) => &b.contents,
+        _ => return Value::Nil,
+    };
+    stmt_list(&stmt.source, body.stmts.iter().cloned())
+}
+
+pub(super) fn comment_elements(stmt: &super::Stmt) -> Option<Value> {
+    let comment = match &*stmt.ast {
+        ast::Stmt::Comment(c) => c,
+        _ => return None,
+    };
+    let mut elements = Vec::new();
+    let mut text: Option<(Span, Vec<Span>, Vec<ast::Link>)> = None;
+    for elem in &comment.elements {
+        match elem {
+            ast::CommentElem::Text { text: t, links } => {
+                let (span, lines, all_links) = text.get_or_insert_with(|| (t.span, Vec::new(), Vec::new()));
+                *span = span.merge(t.span);
+                lines.push(t.span);
+                all_links.extend(links.iter().cloned());
+            }
+            ast::CommentElem::Code { markers, attributes, code, hidden, expected_output } => {
+                if let Some((span, lines, links)) = text.take() {
+                    elements.push(ElementKind::Text { span, lines, links });
+                }
+                if let Some(span) = elem.span() {
+                    elements.push(ElementKind::Snippet {
+                        span,
+                        markers: markers.iter().map(|t| t.span).collect(),
+                        attributes: attributes.clone(),
+                        code: code.clone(),
+                        hidden: hidden.iter().map(|t| t.span).collect(),
+                        expected_output: expected_output.iter().map(|t| t.span).collect(),
+                    });
+                }
+            }
+        }
+    }
+    if let Some((span, lines, links)) = text {
+        elements.push(ElementKind::Text { span, lines, links });
+    }
+    Some(Value::List(Rc::new(super::List::new(
+        elements
+            .into_iter()
+            .map(|kind| Value::CommentElement(Rc::new(CommentElement {
+                source: stmt.source.clone(),
+                kind,
+            })))
+            .collect(),
+    ))))
+}
+
+pub(super) fn comment_element_has_attribute(elem: &Value, name: &Value) -> Result<Value, String> {
+    let elem = match elem {
+        Value::CommentElement(elem) => elem,
         other => return Err(format!(
-            "first argument must be Interpreter but was {}",
+            "first argument must be a comment element but was {}",
             other.type_name(),
         )),
     };
-    let s = match stmt {
-        Value::Stmt(stmt) => stmt,
+    let attr = match name {
+        Value::Str(s) => {
+            let name = s.to_string();
+            ast::SnippetAttr::from_name(&name)
+                .ok_or_else(|| format!("unknown snippet attribute `{}`", name))?
+        }
         other => return Err(format!(
-            "second argument must be Comment or Code but was {}",
+            "attribute name must be Str but was {}",
             other.type_name(),
         )),
     };
-    let env = i.env.borrow().clone();
-    match i.eval.borrow_mut().eval_statement(&s.ast, &env) {
-        Ok(new_env) => {
-            *i.env.borrow_mut() = new_env;
-            Ok(Value::Nil)
+    Ok(Value::Bool(elem.attributes().iter().any(|a| a.kind == attr)))
+}
+
+pub(super) fn stmt_exprs(stmt: &super::Stmt) -> Value {
+    fn if_cond_exprs<'a>(cond: &'a ast::IfCond, exprs: &mut Vec<&'a Rc<ast::Expr>>) {
+        match cond {
+            ast::IfCond::Expr(e) => exprs.push(e),
+            ast::IfCond::TypeTest { ty, value, .. } => {
+                exprs.push(ty);
+                exprs.push(value);
+            }
         }
-        Err(super::EvalStop::Return(_)) => {
-            panic!("return outside of function");
+    }
+    let mut exprs = Vec::new();
+    match &*stmt.ast {
+        ast::Stmt::If { cond, tail, .. } => {
+            if_cond_exprs(cond, &mut exprs);
+            let mut tail: &ast::IfTail = tail;
+            while let ast::IfTail::ElseIf { cond, tail: next_tail, .. } = tail {
+                if_cond_exprs(cond, &mut exprs);
+                tail = next_tail;
+            }
         }
-        Err(super::EvalStop::Error(err)) => {
-            let message = format!(
-                "runtime error on line {}: {}",
-                source.span_start_line(err.span.unwrap()),
-                err.message,
-            );
-            Ok(Value::Str(Rc::new(super::Str::new(&message))))
+        ast::Stmt::While { cond, .. } => if_cond_exprs(cond, &mut exprs),
+        ast::Stmt::Expr { expr, .. } => exprs.push(expr),
+        ast::Stmt::For { iterable, .. } => exprs.push(iterable),
+        ast::Stmt::Return { value, .. } |
+        ast::Stmt::Yield { value, .. } => exprs.extend(value),
+        ast::Stmt::Let { value, .. } => exprs.push(value),
+        ast::Stmt::Comment(_) |
+        ast::Stmt::FnDef(_) |
+        ast::Stmt::StructDef { .. } |
+        ast::Stmt::Block(_) => {}
+    }
+    Value::List(Rc::new(super::List::new(
+        exprs.into_iter().map(|e| super::types::Expr::wrap(&stmt.source, e)).collect(),
+    )))
+}
+
+pub(super) fn expr_kind(expr: &ast::Expr) -> &'static str {
+    match expr {
+        ast::Expr::Name { .. } => "name",
+        ast::Expr::Number { .. } => "number",
+        ast::Expr::Bool { .. } => "bool",
+        ast::Expr::Str { .. } => "str",
+        ast::Expr::Nil { .. } => "nil",
+        ast::Expr::SelfExpr { .. } => "self",
+        ast::Expr::Call { .. } => "call",
+        ast::Expr::Paren { .. } => "paren",
+        ast::Expr::PrefixOp { .. } => "prefix_op",
+        ast::Expr::BinOp { .. } => "binop",
+        ast::Expr::Field { .. } => "field",
+        ast::Expr::AssignVar { .. } => "assign",
+        ast::Expr::AssignField { .. } => "assign_field",
+        ast::Expr::ListComp(_) => "list_comp",
+    }
+}
+
+pub(super) fn expr_children(expr: &super::types::Expr) -> Value {
+    let children: Vec<&Rc<ast::Expr>> = match &*expr.ast {
+        ast::Expr::Name { .. } |
+        ast::Expr::Number { .. } |
+        ast::Expr::Bool { .. } |
+        ast::Expr::Str { .. } |
+        ast::Expr::Nil { .. } |
+        ast::Expr::SelfExpr { .. } => Vec::new(),
+        ast::Expr::Call { func, args, .. } => {
+            std::iter::once(func).chain(args.iter().map(|a| &a.item)).collect()
+        }
+        ast::Expr::Paren { inner, .. } => vec![inner],
+        ast::Expr::PrefixOp { arg, .. } => vec![arg],
+        ast::Expr::BinOp { lhs, rhs, .. } => vec![lhs, rhs],
+        ast::Expr::Field { obj, .. } => vec![obj],
+        ast::Expr::AssignVar { value, .. } => vec![value],
+        ast::Expr::AssignField { obj, value, .. } => vec![obj, value],
+        ast::Expr::ListComp(c) => {
+            let mut children = vec![&c.item, &c.iterable];
+            children.extend(c.filter.as_ref());
+            children
         }
+    };
+    Value::List(Rc::new(super::List::new(
+        children.into_iter().map(|e| super::types::Expr::wrap(&expr.source, e)).collect(),
+    )))
+}
+
+fn stmt_block(stmt: &ast::Stmt) -> Option<&ast::Block> {
+    match stmt {
+        ast::Stmt::FnDef(f) => Some(&f.body),
+        ast::Stmt::While { body, .. } |
+        ast::Stmt::If { body, .. } |
+        ast::Stmt::For { body, .. } => Some(body),
+        ast::Stmt::Block(b) => Some(b),
+        _ => None,
     }
 }
+
+// Source text to insert for a value given to one of the `Code` rewriting
+// methods. `nil` stands for no text at all.
+fn insertion_text(value: &Value, arg: &str) -> Result<Option<String>, String> {
+    match value {
+        Value::Nil => Ok(None),
+        Value::Str(s) => Ok(Some(s.to_string())),
+        Value::Stmt(s) if s.is_code() => Ok(Some(
+            crate::parser::code_content(&s.source, s.ast.span(), &[]),
+        )),
+        Value::Expr(e) => Ok(Some(e.source.text[e.ast.span().source_range()].to_owned())),
+        Value::CommentElement(e) if e.is_snippet() => Ok(Some(e.content(false))),
+        other => Err(format!(
+            "{} argument must be Str, Code or snippet but was {}",
+            arg,
+            other.type_name(),
+        )),
+    }
+}
+
+// Start of the line containing `pos`, markers of comments and snippets
+// containing the code aren't a part of it.
+fn line_start(source: &Source, pos: usize) -> usize {
+    crate::parser::code_line_start(source, pos)
+}
+
+// Indentation of the line containing `pos`.
+fn line_indent(source: &Source, pos: usize) -> &str {
+    let line = &source.text[line_start(source, pos)..pos];
+    &line[..line.len() - line.trim_start().len()]
+}
+
+// Indentation preceding `pos` if nothing else precedes it on its line.
+fn own_line_indent(source: &Source, pos: usize) -> Option<&str> {
+    let indent = line_indent(source, pos);
+    if line_start(source, pos) + indent.len() == pos {
+        Some(indent)
+    } else {
+        None
+    }
+}
+
+// Continuation lines of inserted text are indented to match the first one.
+fn indent_lines(text: &str, indent: &str) -> String {
+    text.lines().collect::<Vec<_>>().join(&format!("\n{}", indent))
+}
+
+// Applies `edits` to the text of `code` and parses the result as a new
+// statement with its own source.
+fn rewrite(code: &super::Stmt, mut edits: Vec<(std::ops::Range<usize>, String)>) -> Value {
+    edits.sort_by_key(|(range, _)| range.start);
+    let content = crate::parser::code_content(&code.source, code.ast.span(), &edits);
+    let program = crate::parser::parse(&content);
+    let source = Rc::new(match &program {
+        Ok(program) => Source::with_program(content, program),
+        Err(_) => Source::new(content),
+    });
+    let error = |message: String, span: Span| Value::Error(Rc::new(super::types::Error {
+        kind: "parse",
+        message,
+        location: Some((source.clone(), span)),
+        code: None,
+    }));
+    match program {
+        Ok(program) if program.code.stmts.len() == 1 => Value::Stmt(Rc::new(super::Stmt {
+            source: source.clone(),
+            ast: program.code.stmts[0].clone(),
+        })),
+        Ok(program) => {
+            let span = match (program.code.stmts.first(), program.code.stmts.last()) {
+                (Some(first), Some(last)) => first.span().merge(last.span()),
+                _ => Span::new(crate::Pos::START, crate::Pos::START),
+            };
+            error(
+                format!("rewritten code has {} statements instead of one", program.code.stmts.len()),
+                span,
+            )
+        }
+        Err(e) => error(e.message, e.span),
+    }
+}
+
+fn rewritten_code(code: &Value) -> Result<&super::Stmt, String> {
+    match code {
+        Value::Stmt(s) if s.is_code() => Ok(s),
+        _ => Err("only Code can be rewritten".to_owned()),
+    }
+}
+
+pub(super) fn code_replace(code: &Value, child: &Value, replacement: &Value) -> Result<Value, String> {
+    let code = rewritten_code(code)?;
+    let (source, span) = match child {
+        Value::Stmt(s) => (&s.source, s.ast.span()),
+        Value::Expr(e) => (&e.source, e.ast.span()),
+        other => return Err(format!(
+            "first argument must be Code or Expr but was {}",
+            other.type_name(),
+        )),
+    };
+    let outer = code.ast.span();
+    if !Rc::ptr_eq(source, &code.source) || span.start < outer.start || span.end > outer.end {
+        return Err("first argument must be a part of the rewritten code".to_owned());
+    }
+    let text = insertion_text(replacement, "second")?.unwrap_or_default();
+    let indent = line_indent(source, span.start.source_pos());
+    Ok(rewrite(code, vec![(span.source_range(), indent_lines(&text, indent))]))
+}
+
+pub(super) fn code_wrap(code: &Value, before: &Value, after: &Value) -> Result<Value, String> {
+    let code = rewritten_code(code)?;
+    let block = match stmt_block(&code.ast) {
+        Some(block) => block,
+        None => return Err(format!("{} has no block to wrap", stmt_kind(&code.ast))),
+    };
+    let before = insertion_text(before, "first")?;
+    let after = insertion_text(after, "second")?;
+    let text = &code.source;
+    let left = block.left_curly.span.end.source_pos();
+    let right = block.right_curly.span.start.source_pos();
+    let outer = line_indent(text, left);
+    let mut edits = Vec::new();
+    let first = match block.contents.stmts.first() {
+        Some(first) => first.span().start.source_pos(),
+        None => {
+            let inner = format!("{}    ", outer);
+            let lines = before
+                .iter()
+                .chain(after.iter())
+                .map(|t| format!("\n{}{}", inner, indent_lines(t, &inner)))
+                .collect::<String>();
+            edits.push((left..right, format!("{}\n{}", lines, outer)));
+            return Ok(rewrite(code, edits));
+        }
+    };
+    match own_line_indent(text, first) {
+        Some(inner) => {
+            if let Some(before) = before {
+                edits.push((left..left, format!("\n{}{}", inner, indent_lines(&before, inner))));
+            }
+            if let Some(after) = after {
+                let after = indent_lines(&after, inner);
+                match own_line_indent(text, right) {
+                    Some(_) => {
+                        let start = line_start(text, right);
+                        edits.push((start..start, format!("{}{}\n", inner, after)));
+                    }
+                    None => edits.push((right..right, format!("\n{}{}\n{}", inner, after, outer))),
+                }
+            }
+        }
+        None => {
+            if let Some(before) = before {
+                edits.push((left..left, format!(" {}", before)));
+            }
+            if let Some(after) = after {
+                edits.push((right..right, format!("{} ", after)));
+            }
+        }
+    }
+    Ok(rewrite(code, edits))
+}
+
+pub(super) fn code_splice(code: &Value, index: &Value, snippet: &Value) -> Result<Value, String> {
+    let code = rewritten_code(code)?;
+    let block = match stmt_block(&code.ast) {
+        Some(block) => block,
+        None => return Err(format!("{} has no block to splice into", stmt_kind(&code.ast))),
+    };
+    let stmts = &block.contents.stmts;
+    let index = match index {
+        Value::Int(i) if *i >= 0 && *i as usize <= stmts.len() => *i as usize,
+        Value::Int(i) => return Err(format!("index {} is out of range for block of length {}", i, stmts.len())),
+        other => return Err(format!("first argument must be Int but was {}", other.type_name())),
+    };
+    let inserted = insertion_text(snippet, "second")?.unwrap_or_default();
+    let text = &code.source;
+    let edit = match (stmts.get(index), stmts.last()) {
+        (Some(stmt), _) => {
+            let pos = stmt.span().start.source_pos();
+            match own_line_indent(text, pos) {
+                Some(indent) => {
+                    let start = line_start(text, pos);
+                    (start..start, format!("{}{}\n", indent, indent_lines(&inserted, indent)))
+                }
+                None => (pos..pos, format!("{} ", inserted)),
+            }
+        }
+        (None, Some(last)) => {
+            let pos = last.span().end.source_pos();
+            let first = stmts[0].span().start.source_pos();
+            match own_line_indent(text, first) {
+                Some(indent) => (pos..pos, format!("\n{}{}", indent, indent_lines(&inserted, indent))),
+                None => (pos..pos, format!(" {}", inserted)),
+            }
+        }
+        (None, None) => {
+            let left = block.left_curly.span.end.source_pos();
+            let right = block.right_curly.span.start.source_pos();
+            let outer = line_indent(text, left);
+            let inner = format!("{}    ", outer);
+            (left..right, format!("\n{}{}\n{}", inner, indent_lines(&inserted, &inner), outer))
+        }
+    };
+    Ok(rewrite(code, vec![edit]))
+}
+
+pub(super) fn edit_source(
+    source: &Rc<Source>,
+    edits: Option<&std::cell::RefCell<Vec<crate::Edit>>>,
+    target: &Value,
+    replacement: &Value,
+) -> Result<Value, String> {
+    let edits = match edits {
+        Some(edits) => edits,
+        None => return Err("editing the program source is not enabled".to_owned()),
+    };
+    let (target_source, span) = match target {
+        Value::Stmt(s) => (&s.source, s.ast.span()),
+        Value::CommentElement(e) => (&e.source, e.span()),
+        Value::Expr(e) => (&e.source, e.ast.span()),
+        Value::Span(s) => (&s.source, s.span),
+        other => return Err(format!(
+            "first argument must be Comment, Code, Expr or Span but was {}",
+            other.type_name(),
+        )),
+    };
+    if !Rc::ptr_eq(target_source, source) {
+        return Err("first argument must be a part of the program source".to_owned());
+    }
+    let replacement = match replacement {
+        Value::Str(s) => s.to_string(),
+        other => return Err(format!(
+            "second argument must be Str but was {}",
+            other.type_name(),
+        )),
+    };
+    let mut edits = edits.borrow_mut();
+    if let Some(other) = edits.iter().find(|e| e.span.start < span.end && span.start < e.span.end) {
+        return Err(format!(
+            "edit overlaps an earlier edit at line {}",
+            source.span_start_line(other.span),
+        ));
+    }
+    edits.push(crate::Edit { span, replacement });
+    Ok(Value::Nil)
+}
@@ -1,9 +1,9 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, io::Write, rc::Rc};
 
-use crate::{ast, Source};
-use super::{Env, Value};
+use crate::{ast, Source, Span};
+use super::{Env, Frame, Value};
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Clone)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
 pub(crate) struct Str {
     pub(crate) chars: Vec<char>,
 }
@@ -32,6 +32,12 @@ impl Str {
                     super::intrinsics::substring(&as_value, idx, len)
                 }))))
             }
+            "chars" => {
+                let as_value = as_value.clone();
+                Some(Value::NativeFunc(Rc::new(NativeFunc::new("chars", move |values| {
+                    super::intrinsics::string_chars(&as_value, values)
+                }))))
+            }
             _ => None,
         }
     }
@@ -51,7 +57,7 @@ impl std::fmt::Display for Str {
 
 pub(crate) struct NativeFunc {
     pub(crate) name: String,
-    pub(crate) f: Box<dyn Fn(&Rc<Source>, &[Value]) -> Result<Value, String>>,
+    pub(crate) f: Box<dyn Fn(&super::Evaluator, &[Value]) -> Result<Value, String>>,
 }
 
 impl NativeFunc {
@@ -65,18 +71,29 @@ impl NativeFunc {
         }
     }
 
-    pub(crate) fn new_src_hack(
+    // for functions that need to know which evaluator is calling them
+    pub(crate) fn with_evaluator(
+        name: impl Into<String>,
+        f: impl Fn(&super::Evaluator, &[Value]) -> Result<Value, String> + 'static,
+    ) -> NativeFunc {
+        NativeFunc {
+            name: name.into(),
+            f: Box::new(f),
+        }
+    }
+
+    pub(crate) fn new1(
         name: impl Into<String>,
-        f: impl Fn(Rc<Source>) -> Result<Value, String> + 'static,
+        f: impl Fn(&Value) -> Result<Value, String> + 'static,
     ) -> NativeFunc {
         let name = name.into();
         NativeFunc {
             name: name.clone(),
-            f: Box::new(move |src, values| {
+            f: Box::new(move |_, values| {
                 match values {
-                    [] => f(src.clone()),
+                    [single] => f(single),
                     _ => Err(format!(
-                        "{} expects 0 arguments, got {}",
+                        "{} expects 1 argument, got {}",
                         name,
                         values.len(),
                     )),
@@ -85,18 +102,18 @@ impl NativeFunc {
         }
     }
 
-    pub(crate) fn new1(
+    pub(crate) fn new2(
         name: impl Into<String>,
-        f: impl Fn(&Value) -> Result<Value, String> + 'static,
+        f: impl Fn(&Value, &Value) -> Result<Value, String> + 'static,
     ) -> NativeFunc {
         let name = name.into();
         NativeFunc {
             name: name.clone(),
             f: Box::new(move |_, values| {
                 match values {
-                    [single] => f(single),
+                    [a, b] => f(a, b),
                     _ => Err(format!(
-                        "{} expects 1 argument, got {}",
+                        "{} expects 2 arguments, got {}",
                         name,
                         values.len(),
                     )),
@@ -105,18 +122,18 @@ impl NativeFunc {
         }
     }
 
-    pub(crate) fn new2(
+    pub(crate) fn new3(
         name: impl Into<String>,
-        f: impl Fn(&Value, &Value) -> Result<Value, String> + 'static,
+        f: impl Fn(&Value, &Value, &Value) -> Result<Value, String> + 'static,
     ) -> NativeFunc {
         let name = name.into();
         NativeFunc {
             name: name.clone(),
             f: Box::new(move |_, values| {
                 match values {
-                    [a, b] => f(a, b),
+                    [a, b, c] => f(a, b, c),
                     _ => Err(format!(
-                        "{} expects 2 arguments, got {}",
+                        "{} expects 3 arguments, got {}",
                         name,
                         values.len(),
                     )),
@@ -128,13 +145,61 @@ impl NativeFunc {
 
 pub(crate) struct UserFunc {
     pub(crate) name: String,
+    pub(crate) source: Rc<Source>,
     pub(crate) def: Rc<ast::FnDef>,
+    // the `FnDef` statement of `def`
+    pub(crate) stmt: Rc<ast::Stmt>,
     pub(crate) env: Env,
 }
 
+impl UserFunc {
+    pub(crate) fn lookup_field(&self, field: &str) -> Option<Value> {
+        match field {
+            "name" => Some(Value::from(self.name.as_str())),
+            "arity" => Some(Value::Int(self.def.params.len() as i64)),
+            "params" => Some(Value::List(Rc::new(List::new(
+                self.def.params
+                    .iter()
+                    .map(|p| Value::from(&self.source.text[p.item.span.source_range()]))
+                    .collect(),
+            )))),
+            "source" => Some(Stmt::wrap(&self.source, Some(self.stmt.clone()))),
+            "doc" => Some(Stmt::wrap(&self.source, self.def.doc.get().cloned())),
+            _ => None,
+        }
+    }
+}
+
 pub(crate) struct Struct {
     pub(crate) name: String,
     pub(crate) ctor: Option<Rc<NativeFunc>>,
+    pub(crate) methods: Vec<Rc<UserFunc>>,
+    // fields that methods assign to `self`, sorted by name
+    pub(crate) fields: Vec<String>,
+}
+
+impl Struct {
+    pub(crate) fn builtin(name: &str, ctor: NativeFunc) -> Rc<Struct> {
+        Rc::new(Struct {
+            name: name.to_owned(),
+            ctor: Some(Rc::new(ctor)),
+            methods: Vec::new(),
+            fields: Default::default(),
+        })
+    }
+
+    pub(crate) fn lookup_field(&self, field: &str) -> Option<Value> {
+        match field {
+            "name" => Some(Value::from(self.name.as_str())),
+            "methods" => Some(Value::List(Rc::new(List::new(
+                self.methods.iter().cloned().map(Value::UserFunc).collect(),
+            )))),
+            "fields" => Some(Value::List(Rc::new(List::new(
+                self.fields.iter().map(|f| Value::from(f.as_str())).collect(),
+            )))),
+            _ => None,
+        }
+    }
 }
 
 pub(crate) struct Instance {
@@ -143,8 +208,20 @@ pub(crate) struct Instance {
 }
 
 impl Instance {
-    pub(crate) fn lookup_field(&self, field: &str) -> Option<Value> {
-        self.fields.borrow().get(field).cloned()
+    // Fields shadow methods of the same name. Methods are bound to the
+    // instance they are looked up on, which they see as `self`.
+    pub(crate) fn lookup_field(&self, as_value: &Value, field: &str) -> Option<Value> {
+        if let Some(value) = self.fields.borrow().get(field) {
+            return Some(value.clone());
+        }
+        let method = self.ty.methods.iter().find(|method| method.name == field)?;
+        Some(Value::UserFunc(Rc::new(UserFunc {
+            name: method.name.clone(),
+            source: method.source.clone(),
+            def: method.def.clone(),
+            stmt: method.stmt.clone(),
+            env: method.env.with_fence().define("self", as_value.clone()),
+        })))
     }
 
     pub(crate) fn set_field(&self, field: &str, value: Value) {
@@ -182,12 +259,169 @@ impl List {
     }
 }
 
+#[derive(PartialEq, Eq, Hash, Clone)]
+pub(crate) enum SetKey {
+    Nil,
+    Int(i64),
+    Bool(bool),
+    Str(Rc<Str>),
+}
+
+impl SetKey {
+    pub(crate) fn from_value(value: &Value) -> Result<SetKey, String> {
+        match value {
+            Value::Nil => Ok(SetKey::Nil),
+            Value::Int(x) => Ok(SetKey::Int(*x)),
+            Value::Bool(x) => Ok(SetKey::Bool(*x)),
+            Value::Str(x) => Ok(SetKey::Str(x.clone())),
+            other => Err(format!("{} cannot be stored in a Set", other.type_name())),
+        }
+    }
+
+    pub(crate) fn to_value(&self) -> Value {
+        match self {
+            SetKey::Nil => Value::Nil,
+            SetKey::Int(x) => Value::Int(*x),
+            SetKey::Bool(x) => Value::Bool(*x),
+            SetKey::Str(x) => Value::Str(x.clone()),
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Set {
+    // keeps insertion order for iteration, removed keys leave holes until
+    // there are too many of them
+    order: RefCell<Vec<Option<SetKey>>>,
+    // position of each key in `order`
+    keys: RefCell<HashMap<SetKey, usize>>,
+}
+
+impl Set {
+    pub(crate) fn new(keys: impl IntoIterator<Item = SetKey>) -> Self {
+        let set = Set::default();
+        for key in keys {
+            set.insert(key);
+        }
+        set
+    }
+
+    pub(crate) fn insert(&self, key: SetKey) -> bool {
+        let mut keys = self.keys.borrow_mut();
+        if keys.contains_key(&key) {
+            return false;
+        }
+        let mut order = self.order.borrow_mut();
+        keys.insert(key.clone(), order.len());
+        order.push(Some(key));
+        true
+    }
+
+    pub(crate) fn contains(&self, key: &SetKey) -> bool {
+        self.keys.borrow().contains_key(key)
+    }
+
+    pub(crate) fn remove(&self, key: &SetKey) -> bool {
+        let mut keys = self.keys.borrow_mut();
+        let idx = match keys.remove(key) {
+            Some(idx) => idx,
+            None => return false,
+        };
+        let mut order = self.order.borrow_mut();
+        order[idx] = None;
+        if order.len() > 2 * keys.len() + 8 {
+            order.retain(Option::is_some);
+            for (idx, key) in order.iter().enumerate() {
+                keys.insert(key.clone().unwrap(), idx);
+            }
+        }
+        true
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.keys.borrow().len()
+    }
+
+    pub(crate) fn keys(&self) -> Vec<SetKey> {
+        self.order.borrow().iter().flatten().cloned().collect()
+    }
+
+    pub(crate) fn values(&self) -> Vec<Value> {
+        self.order.borrow().iter().flatten().map(SetKey::to_value).collect()
+    }
+
+    pub(crate) fn lookup_field(&self, as_value: &Value, field: &str) -> Option<Value> {
+        match field {
+            "len" => Some(Value::Int(self.len() as i64)),
+            "add" => {
+                let as_value = as_value.clone();
+                Some(Value::NativeFunc(Rc::new(NativeFunc::new1("add", move |val| {
+                    super::intrinsics::set_add(&as_value, val)
+                }))))
+            }
+            "has" => {
+                let as_value = as_value.clone();
+                Some(Value::NativeFunc(Rc::new(NativeFunc::new1("has", move |val| {
+                    super::intrinsics::set_has(&as_value, val)
+                }))))
+            }
+            "remove" => {
+                let as_value = as_value.clone();
+                Some(Value::NativeFunc(Rc::new(NativeFunc::new1("remove", move |val| {
+                    super::intrinsics::set_remove(&as_value, val)
+                }))))
+            }
+            "union" => {
+                let as_value = as_value.clone();
+                Some(Value::NativeFunc(Rc::new(NativeFunc::new1("union", move |other| {
+                    super::intrinsics::set_union(&as_value, other)
+                }))))
+            }
+            "intersection" => {
+                let as_value = as_value.clone();
+                Some(Value::NativeFunc(Rc::new(NativeFunc::new1("intersection", move |other| {
+                    super::intrinsics::set_intersection(&as_value, other)
+                }))))
+            }
+            "difference" => {
+                let as_value = as_value.clone();
+                Some(Value::NativeFunc(Rc::new(NativeFunc::new1("difference", move |other| {
+                    super::intrinsics::set_difference(&as_value, other)
+                }))))
+            }
+            _ => None,
+        }
+    }
+}
+
+pub(crate) struct Generator {
+    pub(crate) name: String,
+    pub(crate) source: Rc<Source>,
+    pub(crate) state: RefCell<GeneratorState>,
+}
+
+pub(crate) enum GeneratorState {
+    Suspended(Vec<Frame>),
+    Running,
+    Finished,
+}
+
 pub(crate) struct Stmt {
     pub(crate) source: Rc<Source>,
     pub(crate) ast: Rc<ast::Stmt>,
 }
 
 impl Stmt {
+    fn wrap(source: &Rc<Source>, stmt: Option<Rc<ast::Stmt>>) -> Value {
+        match stmt {
+            Some(ast) => Value::Stmt(Rc::new(Stmt {
+                source: source.clone(),
+                ast,
+            })),
+            None => Value::Nil,
+        }
+    }
+
     pub(crate) fn is_code(&self) -> bool {
         match *self.ast {
             ast::Stmt::While { .. } |
@@ -195,6 +429,7 @@ impl Stmt {
             ast::Stmt::Expr { .. } |
             ast::Stmt::For { .. } |
             ast::Stmt::Return { .. } |
+            ast::Stmt::Yield { .. } |
             ast::Stmt::Let { .. } |
             ast::Stmt::FnDef(_) |
             ast::Stmt::StructDef { .. } |
@@ -203,7 +438,7 @@ impl Stmt {
         }
     }
 
-    pub(crate) fn lookup_field(&self, field: &str) -> Option<Value> {
+    pub(crate) fn lookup_field(&self, as_value: &Value, field: &str) -> Option<Value> {
         match field {
             "text" => {
                 let span = self.ast.span();
@@ -213,6 +448,308 @@ impl Stmt {
             "children" => {
                 Some(super::intrinsics::stmt_children(self))
             }
+            "content" => {
+                let content = match &*self.ast {
+                    ast::Stmt::Comment(c) => crate::parser::comment_content(&self.source.text, c),
+                    _ => crate::parser::code_content(&self.source, self.ast.span(), &[]),
+                };
+                Some(Value::from(content.as_str()))
+            }
+            "elements" => super::intrinsics::comment_elements(self),
+            "doc" if self.is_code() => Some(Stmt::wrap(&self.source, self.ast.doc().cloned())),
+            "target" => match &*self.ast {
+                ast::Stmt::Comment(c) => Some(Stmt::wrap(&self.source, c.target.borrow().upgrade())),
+                _ => None,
+            },
+            "kind" => Some(Value::from(super::intrinsics::stmt_kind(&self.ast))),
+            "expr" => match &*self.ast {
+                ast::Stmt::Expr { expr, .. } => Some(Expr::wrap(&self.source, expr)),
+                _ => Some(Value::Nil),
+            },
+            "exprs" => Some(super::intrinsics::stmt_exprs(self)),
+            "name" => Some(super::intrinsics::stmt_name(self)),
+            "params" => Some(super::intrinsics::stmt_params(self)),
+            "body" => Some(super::intrinsics::stmt_body(self)),
+            "line" => Some(Value::Int(self.source.span_start_line(self.ast.span()) as i64)),
+            "column" => Some(Value::Int(self.source.span_start_column(self.ast.span()) as i64)),
+            "span" => Some(SourceSpan::wrap(&self.source, self.ast.span())),
+            "replace" if self.is_code() => {
+                let as_value = as_value.clone();
+                Some(Value::NativeFunc(Rc::new(NativeFunc::new2("replace", move |child, replacement| {
+                    super::intrinsics::code_replace(&as_value, child, replacement)
+                }))))
+            }
+            "wrap" if self.is_code() => {
+                let as_value = as_value.clone();
+                Some(Value::NativeFunc(Rc::new(NativeFunc::new2("wrap", move |before, after| {
+                    super::intrinsics::code_wrap(&as_value, before, after)
+                }))))
+            }
+            "splice" if self.is_code() => {
+                let as_value = as_value.clone();
+                Some(Value::NativeFunc(Rc::new(NativeFunc::new2("splice", move |index, snippet| {
+                    super::intrinsics::code_splice(&as_value, index, snippet)
+                }))))
+            }
+            _ => None,
+        }
+    }
+}
+
+pub(crate) struct Expr {
+    pub(crate) source: Rc<Source>,
+    pub(crate) ast: Rc<ast::Expr>,
+}
+
+impl Expr {
+    pub(crate) fn wrap(source: &Rc<Source>, expr: &Rc<ast::Expr>) -> Value {
+        Value::Expr(Rc::new(Expr {
+            source: source.clone(),
+            ast: expr.clone(),
+        }))
+    }
+
+    pub(crate) fn lookup_field(&self, field: &str) -> Option<Value> {
+        let span = self.ast.span();
+        match field {
+            "kind" => Some(Value::from(super::intrinsics::expr_kind(&self.ast))),
+            "text" => Some(Value::from(&self.source.text[span.source_range()])),
+            "children" => Some(super::intrinsics::expr_children(self)),
+            "line" => Some(Value::Int(self.source.span_start_line(span) as i64)),
+            "column" => Some(Value::Int(self.source.span_start_column(span) as i64)),
+            "span" => Some(SourceSpan::wrap(&self.source, span)),
+            "operator" => match &*self.ast {
+                ast::Expr::PrefixOp { operator, .. } |
+                ast::Expr::BinOp { operator, .. } => {
+                    Some(Value::from(&self.source.text[operator.span.source_range()]))
+                }
+                _ => Some(Value::Nil),
+            },
+            "lhs" => match &*self.ast {
+                ast::Expr::BinOp { lhs, .. } => Some(Expr::wrap(&self.source, lhs)),
+                _ => Some(Value::Nil),
+            },
+            "rhs" => match &*self.ast {
+                ast::Expr::BinOp { rhs, .. } => Some(Expr::wrap(&self.source, rhs)),
+                _ => Some(Value::Nil),
+            },
+            "callee" => match &*self.ast {
+                ast::Expr::Call { func, .. } => Some(Expr::wrap(&self.source, func)),
+                _ => Some(Value::Nil),
+            },
+            "args" => match &*self.ast {
+                ast::Expr::Call { args, .. } => Some(Value::List(Rc::new(List::new(
+                    args.iter().map(|arg| Expr::wrap(&self.source, &arg.item)).collect(),
+                )))),
+                _ => Some(Value::Nil),
+            },
+            "obj" => match &*self.ast {
+                ast::Expr::Field { obj, .. } |
+                ast::Expr::AssignField { obj, .. } => Some(Expr::wrap(&self.source, obj)),
+                _ => Some(Value::Nil),
+            },
+            "name" => {
+                let name = match &*self.ast {
+                    ast::Expr::Name { name } |
+                    ast::Expr::AssignVar { name, .. } => name,
+                    ast::Expr::Field { field, .. } |
+                    ast::Expr::AssignField { field, .. } => field,
+                    _ => return Some(Value::Nil),
+                };
+                Some(Value::from(&self.source.text[name.span.source_range()]))
+            }
+            "value" => match &*self.ast {
+                ast::Expr::Number { value, .. } => Some(Value::Int(*value)),
+                ast::Expr::Bool { value, .. } => Some(Value::Bool(*value)),
+                ast::Expr::Str { value, .. } => Some(Value::from(value.as_str())),
+                _ => Some(Value::Nil),
+            },
+            _ => None,
+        }
+    }
+}
+
+// A paragraph of text or a code snippet inside of a comment. Consecutive
+// lines of text are merged into a single element.
+pub(crate) struct CommentElement {
+    pub(crate) source: Rc<Source>,
+    pub(crate) kind: ElementKind,
+}
+
+pub(crate) enum ElementKind {
+    Text {
+        span: Span,
+        // text of each line, following its markers
+        lines: Vec<Span>,
+        links: Vec<ast::Link>,
+    },
+    Snippet {
+        span: Span,
+        // one for each line of code, including the attribute line
+        markers: Vec<Span>,
+        attributes: Vec<ast::Attribute>,
+        code: ast::NakedBlock,
+        // lines with `>>` markers, they run but are kept out of the content
+        hidden: Vec<Span>,
+        // lines with `=>`, kept out of the content
+        expected_output: Vec<Span>,
+    },
+}
+
+impl CommentElement {
+    pub(crate) fn is_snippet(&self) -> bool {
+        matches!(self.kind, ElementKind::Snippet { .. })
+    }
+
+    pub(crate) fn span(&self) -> Span {
+        match &self.kind {
+            ElementKind::Text { span, .. } => *span,
+            ElementKind::Snippet { span, .. } => *span,
+        }
+    }
+
+    pub(crate) fn lookup_field(&self, as_value: &Value, field: &str) -> Option<Value> {
+        let span = self.span();
+        match field {
+            "kind" => Some(Value::from(if self.is_snippet() { "snippet" } else { "text" })),
+            "text" => Some(Value::from(&self.source.text[span.source_range()])),
+            "content" => Some(Value::from(self.content(false).as_str())),
+            // content of a snippet including its hidden lines
+            "full_content" => Some(Value::from(self.content(true).as_str())),
+            "line" => Some(Value::Int(self.source.span_start_line(span) as i64)),
+            "column" => Some(Value::Int(self.source.span_start_column(span) as i64)),
+            "span" => Some(SourceSpan::wrap(&self.source, span)),
+            "code" => match &self.kind {
+                ElementKind::Text { .. } => Some(Value::Nil),
+                ElementKind::Snippet { code, .. } => {
+                    Some(super::intrinsics::stmt_list(&self.source, code.stmts.iter().cloned()))
+                }
+            },
+            "expected_output" => Some(match &self.kind {
+                ElementKind::Snippet { expected_output, .. } if !expected_output.is_empty() => {
+                    let output = crate::parser::expected_output(&self.source.text, expected_output);
+                    Value::from(output.as_str())
+                }
+                _ => Value::Nil,
+            }),
+            // definitions referenced by `[name]` links, nil for broken links
+            "links" => Some(Value::List(Rc::new(super::List::new(match &self.kind {
+                ElementKind::Text { links, .. } => links
+                    .iter()
+                    .map(|link| Stmt::wrap(&self.source, self.source.link_target(link).cloned()))
+                    .collect(),
+                ElementKind::Snippet { .. } => Vec::new(),
+            })))),
+            "attributes" => Some(Value::List(Rc::new(super::List::new(
+                self.attributes().iter().map(|a| Value::from(a.kind.name())).collect(),
+            )))),
+            "has_attribute" => {
+                let as_value = as_value.clone();
+                Some(Value::NativeFunc(Rc::new(NativeFunc::new1("has_attribute", move |name| {
+                    super::intrinsics::comment_element_has_attribute(&as_value, name)
+                }))))
+            }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn content(&self, with_hidden: bool) -> String {
+        let starts = match &self.kind {
+            ElementKind::Text { lines, .. } => lines.iter().map(|line| line.start.source_pos()).collect(),
+            ElementKind::Snippet { markers, attributes, hidden, .. } => markers
+                .iter()
+                .skip(if attributes.is_empty() { 0 } else { 1 })
+                .filter(|marker| with_hidden || !hidden.contains(marker))
+                .map(|marker| marker.end.source_pos())
+                .collect::<Vec<_>>(),
+        };
+        crate::parser::lines_content(&self.source.text, starts.into_iter())
+    }
+
+    pub(crate) fn attributes(&self) -> &[ast::Attribute] {
+        match &self.kind {
+            ElementKind::Text { .. } => &[],
+            ElementKind::Snippet { attributes, .. } => attributes,
+        }
+    }
+}
+
+// Successful result of `Interpreter.run`.
+pub(crate) struct RunResult {
+    // value of the trailing expression statement, if any
+    pub(crate) value: Value,
+}
+
+impl RunResult {
+    pub(crate) fn lookup_field(&self, field: &str) -> Option<Value> {
+        match field {
+            "value" => Some(self.value.clone()),
+            _ => None,
+        }
+    }
+}
+
+// Error reported to the program as a value instead of stopping it.
+pub(crate) struct Error {
+    // what kind of error it is, e.g. "parse"
+    pub(crate) kind: &'static str,
+    pub(crate) message: String,
+    pub(crate) location: Option<(Rc<Source>, Span)>,
+    // code that was running when the error happened
+    pub(crate) code: Option<Rc<Stmt>>,
+}
+
+impl Error {
+    pub(crate) fn lookup_field(&self, field: &str) -> Option<Value> {
+        match field {
+            "kind" => Some(Value::from(self.kind)),
+            "message" => Some(Value::from(self.message.as_str())),
+            "line" => Some(match &self.location {
+                Some((source, span)) => Value::Int(source.span_start_line(*span) as i64),
+                None => Value::Nil,
+            }),
+            "column" => Some(match &self.location {
+                Some((source, span)) => Value::Int(source.span_start_column(*span) as i64),
+                None => Value::Nil,
+            }),
+            "span" => Some(match &self.location {
+                Some((source, span)) => SourceSpan::wrap(source, *span),
+                None => Value::Nil,
+            }),
+            "code" => Some(match &self.code {
+                Some(code) => Value::Stmt(code.clone()),
+                None => Value::Nil,
+            }),
+            _ => None,
+        }
+    }
+}
+
+// Location of a statement in its source. Offsets are counted in chars, the
+// same way `Str` indexes are.
+pub(crate) struct SourceSpan {
+    pub(crate) source: Rc<Source>,
+    pub(crate) span: Span,
+}
+
+impl SourceSpan {
+    pub(crate) fn wrap(source: &Rc<Source>, span: Span) -> Value {
+        Value::Span(Rc::new(SourceSpan {
+            source: source.clone(),
+            span,
+        }))
+    }
+
+    pub(crate) fn lookup_field(&self, field: &str) -> Option<Value> {
+        let source = &self.source;
+        match field {
+            "start" => Some(Value::Int(source.pos_char_offset(self.span.start) as i64)),
+            "end" => Some(Value::Int(source.pos_char_offset(self.span.end) as i64)),
+            "line" => Some(Value::Int(source.pos_line(self.span.start) as i64)),
+            "column" => Some(Value::Int(source.pos_column(self.span.start) as i64)),
+            "end_line" => Some(Value::Int(source.pos_line(self.span.end) as i64)),
+            "end_column" => Some(Value::Int(source.pos_column(self.span.end) as i64)),
+            "text" => Some(Value::from(&source.text[self.span.source_range()])),
             _ => None,
         }
     }
@@ -221,15 +758,74 @@ impl Stmt {
 pub(crate) struct Interpreter {
     pub(crate) eval: RefCell<super::Evaluator>,
     pub(crate) env: RefCell<Env>,
+    pub(crate) output: InterpreterOutput,
+}
+
+pub(crate) enum InterpreterOutput {
+    // text printed by the interpreted code
+    Captured(Rc<RefCell<String>>),
+    // printed text goes to the output of the code that created the interpreter
+    Forwarded(Rc<RefCell<Box<dyn Write>>>),
 }
 
 impl Interpreter {
-    pub(crate) fn new(src: Rc<Source>) -> Value {
-        let eval = super::Evaluator::new(src, None, Box::new(std::io::sink()));
+    pub(crate) fn new(parent: &super::Evaluator, forward_output: bool, copy_globals: bool) -> Value {
+        let output = if forward_output {
+            InterpreterOutput::Forwarded(parent.output.clone())
+        } else {
+            InterpreterOutput::Captured(Default::default())
+        };
+        let eval = Interpreter::evaluator(parent, &output);
+        if copy_globals {
+            eval.globals.copy_missing(&parent.globals);
+        }
+        Interpreter::wrap(eval, output)
+    }
+
+    // Creates an independent interpreter starting from the current state of
+    // this one. Later assignments in either of them aren't seen by the other,
+    // and suspended generators continue separately in each of them. Forking
+    // fails if one of the generators is running.
+    pub(crate) fn fork(&self) -> Result<Value, String> {
+        let this = match self.eval.try_borrow() {
+            Ok(eval) => eval,
+            Err(_) => return Err("Interpreter is already running".to_owned()),
+        };
+        let output = match &self.output {
+            InterpreterOutput::Captured(_) => InterpreterOutput::Captured(Default::default()),
+            InterpreterOutput::Forwarded(target) => InterpreterOutput::Forwarded(target.clone()),
+        };
+        let eval = Interpreter::evaluator(&this, &output);
+        eval.globals.fork_from(&self.env.borrow())?;
+        *eval.limits.borrow_mut() = this.limits.borrow().fresh();
+        Ok(Interpreter::wrap(eval, output))
+    }
+
+    // Interpreters share builtin types with their parent, so that values can
+    // be passed between them and still match type checks.
+    fn evaluator(parent: &super::Evaluator, output: &InterpreterOutput) -> super::Evaluator {
+        let writer: Box<dyn Write> = match output {
+            InterpreterOutput::Captured(text) => Box::new(CapturedOutput(text.clone())),
+            InterpreterOutput::Forwarded(target) => Box::new(ForwardedOutput(target.clone())),
+        };
+        let mut eval = super::Evaluator::with_builtins(
+            parent.source.clone(),
+            None,
+            writer,
+            &Default::default(),
+            parent.builtins.clone(),
+        );
+        // code run by the interpreter uses the stack of its parent
+        eval.total_call_depth = parent.total_call_depth.clone();
+        eval
+    }
+
+    fn wrap(eval: super::Evaluator, output: InterpreterOutput) -> Value {
         let env = eval.globals.clone();
         Value::Interpreter(Rc::new(Interpreter {
             eval: RefCell::new(eval),
             env: RefCell::new(env),
+            output,
         }))
     }
 
@@ -237,12 +833,79 @@ impl Interpreter {
         match field {
             "run" => {
                 let as_value = as_value.clone();
-                let source = self.eval.borrow().source.clone();
                 Some(Value::NativeFunc(Rc::new(NativeFunc::new1("run", move |val| {
-                    super::intrinsics::interpreter_run(&source, &as_value, val)
+                    super::intrinsics::interpreter_run(&as_value, val)
+                }))))
+            }
+            "output" => {
+                let output = match &self.output {
+                    InterpreterOutput::Captured(text) => text.borrow().clone(),
+                    InterpreterOutput::Forwarded(_) => String::new(),
+                };
+                Some(Value::from(output.as_str()))
+            }
+            "get" => {
+                let as_value = as_value.clone();
+                Some(Value::NativeFunc(Rc::new(NativeFunc::new1("get", move |name| {
+                    super::intrinsics::interpreter_get(&as_value, name)
+                }))))
+            }
+            "set" => {
+                let as_value = as_value.clone();
+                Some(Value::NativeFunc(Rc::new(NativeFunc::new2("set", move |name, value| {
+                    super::intrinsics::interpreter_set(&as_value, name, value)
+                }))))
+            }
+            "names" => {
+                let as_value = as_value.clone();
+                Some(Value::NativeFunc(Rc::new(NativeFunc::new("names", move |values| {
+                    super::intrinsics::interpreter_names(&as_value, values)
+                }))))
+            }
+            "fork" => {
+                let as_value = as_value.clone();
+                Some(Value::NativeFunc(Rc::new(NativeFunc::new("fork", move |values| {
+                    super::intrinsics::interpreter_fork(&as_value, values)
+                }))))
+            }
+            "set_limit" => {
+                let as_value = as_value.clone();
+                Some(Value::NativeFunc(Rc::new(NativeFunc::new2("set_limit", move |name, limit| {
+                    super::intrinsics::interpreter_set_limit(&as_value, name, limit)
+                }))))
+            }
+            "take_output" => {
+                let as_value = as_value.clone();
+                Some(Value::NativeFunc(Rc::new(NativeFunc::new("take_output", move |values| {
+                    super::intrinsics::interpreter_take_output(&as_value, values)
                 }))))
             }
             _ => None,
         }
     }
 }
+
+struct CapturedOutput(Rc<RefCell<String>>);
+
+impl Write for CapturedOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().push_str(&String::from_utf8_lossy(buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+struct ForwardedOutput(Rc<RefCell<Box<dyn Write>>>);
+
+impl Write for ForwardedOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
@@ -18,6 +18,8 @@ pub(crate) enum TokenKind {
     In,
     #[token("return")]
     Return,
+    #[token("yield")]
+    Yield,
     #[token("struct")]
     Struct,
     #[token("true")]
@@ -62,6 +64,10 @@ pub(crate) enum TokenKind {
     EqEq,
     #[token("!=")]
     NotEq,
+    #[token("?.")]
+    QuestionDot,
+    #[token("??")]
+    QuestionQuestion,
     #[token("(")]
     LeftParen,
     #[token(")")]
@@ -70,14 +76,20 @@ pub(crate) enum TokenKind {
     LeftCurly,
     #[token("}")]
     RightCurly,
+    #[token("[")]
+    LeftBracket,
+    #[token("]")]
+    RightBracket,
     #[token(",")]
     Comma,
     #[token(";")]
     Semicolon,
-    #[regex("[a-zA-Z_][a-zA-Z0-9_]*")]
+    #[regex(r"[\p{XID_Start}_]\p{XID_Continue}*")]
     Name,
-    #[regex("[0-9][a-zA-Z0-9_]*")]
+    #[regex(r"[0-9]\p{XID_Continue}*")]
     Number,
+    #[regex(r"@[\p{XID_Start}_]\p{XID_Continue}*")]
+    Attribute,
     #[token("#")]
     CommentMarker,
     CodeMarker,
@@ -101,6 +113,7 @@ impl TokenKind {
             TokenKind::For => "`for`",
             TokenKind::In => "`in`",
             TokenKind::Return => "`return`",
+            TokenKind::Yield => "`yield`",
             TokenKind::Struct => "`struct`",
             TokenKind::True => "`true`",
             TokenKind::False => "`false`",
@@ -123,14 +136,19 @@ impl TokenKind {
             TokenKind::GreaterEq => "`>=`",
             TokenKind::EqEq => "`==`",
             TokenKind::NotEq => "`!=`",
+            TokenKind::QuestionDot => "`?.`",
+            TokenKind::QuestionQuestion => "`??`",
             TokenKind::LeftParen => "`(`",
             TokenKind::RightParen => "`)`",
             TokenKind::LeftCurly => "`{`",
             TokenKind::RightCurly => "`}`",
+            TokenKind::LeftBracket => "`[`",
+            TokenKind::RightBracket => "`]`",
             TokenKind::Comma => "`,`",
             TokenKind::Semicolon => "`;`",
             TokenKind::Name => "identifier",
             TokenKind::Number => "number",
+            TokenKind::Attribute => "attribute",
             TokenKind::CommentMarker => "`#`",
             TokenKind::CodeMarker => "`>`",
             TokenKind::Space => "whitespace",
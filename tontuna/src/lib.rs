@@ -4,7 +4,7 @@ mod lexer;
 mod parser;
 mod pos;
 
-use std::{io::Write, rc::Rc};
+use std::{collections::HashMap, io::Write, rc::Rc};
 
 pub use crate::pos::{Pos, Span};
 
@@ -15,20 +15,112 @@ pub struct Ast {
 }
 
 pub fn parse(source: &str) -> Result<Ast, Error> {
+    let program = crate::parser::parse(source)?;
     Ok(Ast {
-        source: Rc::new(Source::new(source.to_owned())),
-        program: crate::parser::parse(source)?,
+        source: Rc::new(Source::with_program(source.to_owned(), &program)),
+        program,
     })
 }
 
+// Checks a parsed program for problems that don't stop it from running, like
+// `[name]` links in comments to undefined names.
+pub fn check(ast: &Ast) -> Vec<Error> {
+    crate::parser::broken_links(&ast.source, &ast.program.code)
+}
+
 pub fn eval(ast: &Ast, output: Box<dyn Write>) -> Result<(), Error> {
+    eval_with_options(ast, output, &EvalOptions::default()).map(|_| ())
+}
+
+// Runs the program like `eval`, returning the edits of its own source
+// that the program requested.
+pub fn eval_with_options(ast: &Ast, output: Box<dyn Write>, options: &EvalOptions) -> Result<Vec<Edit>, Error> {
     let mut evaluator = eval::Evaluator::new(
         ast.source.clone(),
         Some(&ast.program),
         output,
+        options,
     );
     evaluator.run_program(&ast.program)
-        .map_err(|e| Error { span: e.span.unwrap(), message: e.message })
+        .map_err(|e| Error { span: e.span.unwrap(), message: e.message })?;
+    Ok(evaluator.take_edits())
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct EvalOptions {
+    // Lets the program request edits of its own source with `edit_source`.
+    pub allow_edits: bool,
+    // Runs code snippets in comments where they appear, in the local scope.
+    pub run_comments: bool,
+}
+
+// Replacement of a span of the program source, requested by the program.
+#[derive(Debug, Clone)]
+pub struct Edit {
+    pub span: Span,
+    pub replacement: String,
+}
+
+// Applies non-overlapping edits to the source they were requested for.
+pub fn apply_edits(source: &str, edits: &[Edit]) -> String {
+    let mut edits = edits.iter().collect::<Vec<_>>();
+    edits.sort_by_key(|e| e.span.start);
+    let mut result = String::new();
+    let mut last = 0;
+    for edit in edits {
+        let range = edit.span.source_range();
+        result.push_str(&source[last..range.start]);
+        result.push_str(&edit.replacement);
+        last = range.end;
+    }
+    result.push_str(&source[last..]);
+    result
+}
+
+// Shows the lines changed by the edits as a diff, without any context lines.
+pub fn edits_diff(file: &str, source: &str, edits: &[Edit]) -> String {
+    let mut edits = edits.to_vec();
+    edits.sort_by_key(|e| e.span.start);
+    let line_start = |pos: usize| source[..pos].rfind('\n').map(|idx| idx + 1).unwrap_or(0);
+    let line_end = |pos: usize| source[pos..].find('\n').map(|idx| pos + idx + 1).unwrap_or(source.len());
+
+    let mut diff = format!("--- {}\n+++ {} (requested edits)\n", file, file);
+    let mut line_shift = 0;
+    let mut idx = 0;
+    while idx < edits.len() {
+        // edits touching the same lines are shown in a single hunk
+        let start = line_start(edits[idx].span.source_range().start);
+        let mut end = line_end(edits[idx].span.source_range().end);
+        let mut hunk_end = idx + 1;
+        while hunk_end < edits.len() && edits[hunk_end].span.source_range().start < end {
+            end = std::cmp::max(end, line_end(edits[hunk_end].span.source_range().end));
+            hunk_end += 1;
+        }
+        let old = &source[start..end];
+        let mut new = String::new();
+        let mut last = start;
+        for edit in &edits[idx..hunk_end] {
+            let range = edit.span.source_range();
+            new.push_str(&source[last..range.start]);
+            new.push_str(&edit.replacement);
+            last = range.end;
+        }
+        new.push_str(&source[last..end]);
+
+        let old_line = source[..start].matches('\n').count() as i64 + 1;
+        let old_count = old.lines().count() as i64;
+        let new_count = new.lines().count() as i64;
+        diff += &format!("@@ -{},{} +{},{} @@\n", old_line, old_count, old_line + line_shift, new_count);
+        for line in old.lines() {
+            diff += &format!("-{}\n", line);
+        }
+        for line in new.lines() {
+            diff += &format!("+{}\n", line);
+        }
+        line_shift += new_count - old_count;
+        idx = hunk_end;
+    }
+    diff
 }
 
 pub fn tokens(source: &str) -> impl Iterator<Item = Token> + '_ {
@@ -71,6 +163,7 @@ impl TokenKind {
             crate::lexer::TokenKind::For |
             crate::lexer::TokenKind::In |
             crate::lexer::TokenKind::Return |
+            crate::lexer::TokenKind::Yield |
             crate::lexer::TokenKind::Struct |
             crate::lexer::TokenKind::True |
             crate::lexer::TokenKind::False |
@@ -78,6 +171,7 @@ impl TokenKind {
             crate::lexer::TokenKind::SelfKw => Some(TokenKind::Keyword),
             crate::lexer::TokenKind::Str => Some(TokenKind::String),
             crate::lexer::TokenKind::Dot |
+            crate::lexer::TokenKind::QuestionDot |
             crate::lexer::TokenKind::Colon => Some(TokenKind::Punctuation),
             crate::lexer::TokenKind::Equals |
             crate::lexer::TokenKind::And |
@@ -92,15 +186,19 @@ impl TokenKind {
             crate::lexer::TokenKind::Greater |
             crate::lexer::TokenKind::GreaterEq |
             crate::lexer::TokenKind::EqEq |
-            crate::lexer::TokenKind::NotEq => Some(TokenKind::Operator),
+            crate::lexer::TokenKind::NotEq |
+            crate::lexer::TokenKind::QuestionQuestion => Some(TokenKind::Operator),
             crate::lexer::TokenKind::LeftParen |
             crate::lexer::TokenKind::RightParen |
             crate::lexer::TokenKind::LeftCurly |
             crate::lexer::TokenKind::RightCurly |
+            crate::lexer::TokenKind::LeftBracket |
+            crate::lexer::TokenKind::RightBracket |
             crate::lexer::TokenKind::Comma |
             crate::lexer::TokenKind::Semicolon => Some(TokenKind::Punctuation),
             crate::lexer::TokenKind::Name => Some(TokenKind::Value),
             crate::lexer::TokenKind::Number => Some(TokenKind::Number),
+            crate::lexer::TokenKind::Attribute => Some(TokenKind::Keyword),
             crate::lexer::TokenKind::CommentMarker |
             crate::lexer::TokenKind::CodeMarker => Some(TokenKind::Comment),
             crate::lexer::TokenKind::Space |
@@ -115,6 +213,10 @@ impl TokenKind {
 pub struct Source {
     text: String,
     line_starts: Vec<usize>,
+    // markers of the parsed program, sorted by position
+    markers: Vec<Span>,
+    // top-level definitions of the parsed program by name
+    definitions: HashMap<String, Rc<ast::Stmt>>,
 }
 
 impl Source {
@@ -123,13 +225,50 @@ impl Source {
         line_starts.extend(source
             .char_indices()
             .filter_map(|(idx, ch)| if ch == '\n' { Some(idx + 1) } else { None }));
-        Source { text: source, line_starts }
+        Source {
+            text: source,
+            line_starts,
+            markers: Vec::new(),
+            definitions: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn with_program(source: String, program: &ast::Program) -> Source {
+        Source {
+            markers: crate::parser::markers(program),
+            definitions: crate::parser::definitions(&source, &program.code),
+            ..Source::new(source)
+        }
+    }
+
+    // Links are resolved when they are read, so they point to definitions
+    // for as long as the source is around.
+    pub(crate) fn link_target(&self, link: &ast::Link) -> Option<&Rc<ast::Stmt>> {
+        self.definitions.get(&self.text[link.name.span.source_range()])
     }
 
     pub fn span_start_line(&self, span: Span) -> u32 {
-        match self.line_starts.binary_search(&span.start.source_pos()) {
+        self.pos_line(span.start)
+    }
+
+    pub fn span_start_column(&self, span: Span) -> u32 {
+        self.pos_column(span.start)
+    }
+
+    pub(crate) fn pos_line(&self, pos: Pos) -> u32 {
+        match self.line_starts.binary_search(&pos.source_pos()) {
             Ok(idx) => idx as u32 + 1,
             Err(idx) => idx as u32,
         }
     }
+
+    // column is counted in chars, starting from 1
+    pub(crate) fn pos_column(&self, pos: Pos) -> u32 {
+        let line_start = self.line_starts[self.pos_line(pos) as usize - 1];
+        self.text[line_start..pos.source_pos()].chars().count() as u32 + 1
+    }
+
+    pub(crate) fn pos_char_offset(&self, pos: Pos) -> usize {
+        self.text[..pos.source_pos()].chars().count()
+    }
 }
@@ -10,9 +10,18 @@ struct Opt {
     /// Output file, prints to stdout if not specified
     #[structopt(short, long, parse(from_os_str))]
     output: Option<PathBuf>,
-    /// Only check for parse errors
+    /// Only check for parse errors and broken links in comments
     #[structopt(short, long)]
     check: bool,
+    /// Apply edits requested by the program to the input file after a successful run
+    #[structopt(long)]
+    apply_edits: bool,
+    /// Print requested edits as a diff instead of applying them
+    #[structopt(long, requires = "apply-edits")]
+    dry_run: bool,
+    /// Run code snippets in comments where they appear, e.g. as inline assertions
+    #[structopt(long)]
+    run_comments: bool,
 }
 
 fn main() {
@@ -34,6 +43,13 @@ fn main() {
     };
 
     if opt.check {
+        let errors = tontuna::check(&ast);
+        if !errors.is_empty() {
+            if print_diagnostics(&path.to_string_lossy(), &source, errors.into_iter()).is_err() {
+                std::process::exit(2);
+            }
+            std::process::exit(1);
+        }
         return;
     }
 
@@ -52,8 +68,23 @@ fn main() {
         None => Box::new(std::io::stdout()),
     };
 
-    match tontuna::eval(&ast, output) {
-        Ok(()) => {}
+    let options = tontuna::EvalOptions {
+        allow_edits: opt.apply_edits,
+        run_comments: opt.run_comments,
+    };
+    match tontuna::eval_with_options(&ast, output, &options) {
+        Ok(edits) if edits.is_empty() => {}
+        Ok(edits) if opt.dry_run => {
+            // kept apart from the output of the program
+            eprint!("{}", tontuna::edits_diff(&path.to_string_lossy(), &source, &edits));
+        }
+        Ok(edits) => {
+            if let Err(e) = std::fs::write(&path, tontuna::apply_edits(&source, &edits)) {
+                eprintln!("error: cannot write {}", path.display());
+                eprintln!("    {}", e);
+                std::process::exit(1);
+            }
+        }
         Err(mut e) => {
             e.message = format!("runtime error: {}", e.message);
             print_diagnostic(&path.to_string_lossy(), &source, e);
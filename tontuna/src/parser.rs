@@ -1,4 +1,4 @@
-use std::rc::Rc;
+use std::{collections::HashMap, ops::Range, rc::Rc};
 
 use crate::{
     ast::{self, TokenKind},
@@ -9,27 +9,14 @@ use crate::{
 
 type Result<T> = std::result::Result<T, Error>;
 
-trait NakedBlockExt {
-    fn consume_stmts(self) -> Vec<ast::Stmt>;
-}
-
-impl NakedBlockExt for ast::NakedBlock {
-    fn consume_stmts(self) -> Vec<ast::Stmt> {
-        self.stmts
-            .into_iter()
-            .map(|s| match Rc::try_unwrap(s) {
-                Ok(s) => s,
-                Err(_) => panic!("statement was aliased during parsing"),
-            })
-            .collect()
-    }
-}
-
 #[derive(Debug, Clone, Copy)]
 struct Line<'a> {
     start_pos: Pos,
     text: &'a str,
     levels: u32,
+    // set after stripping a `>>` marker: the line is a part of a snippet
+    // that runs, but isn't shown in its content
+    hidden: bool,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -53,6 +40,15 @@ impl Marker {
         }
     }
 
+    // Length of the marker at the start of `text`, code markers of hidden
+    // lines are doubled.
+    fn len(self, text: &str) -> usize {
+        match self {
+            Marker::Code if text.starts_with(">>") => 2,
+            _ => 1,
+        }
+    }
+
     fn into_token_kind(self) -> TokenKind {
         match self {
             Marker::Code => TokenKind::CodeMarker,
@@ -93,29 +89,31 @@ impl<'a> Line<'a> {
         let mut level = first_level;
         while let Some((marker, offset)) = find_leading_marker(trail_text) {
             if marker == level {
-                trail_text = &trail_text[(offset + 1)..];
+                trail_text = &trail_text[(offset + marker.len(&trail_text[offset..]))..];
                 level = level.other();
                 levels += 1;
             } else {
                 break;
             }
         }
-        Line { start_pos, text, levels }
+        Line { start_pos, text, levels, hidden: false }
     }
 
     fn strip_one_marker(&self) -> (ast::Token, Line<'a>) {
         assert!(self.levels != 0);
         let (kind, offset) = find_leading_marker(self.text).unwrap();
+        let len = kind.len(&self.text[offset..]);
         let token_start = self.start_pos.plus_text(&self.text[..offset]);
-        let token_end = token_start.plus_text(kind.text());
+        let token_end = token_start.plus_text(&self.text[offset..(offset + len)]);
         let token = ast::Token {
             kind: kind.into_token_kind(),
             span: Span::new(token_start, token_end),
         };
         let line = Line {
             start_pos: token_end,
-            text: &self.text[(offset + 1)..],
+            text: &self.text[(offset + len)..],
             levels: self.levels - 1,
+            hidden: len == 2,
         };
         (token, line)
     }
@@ -125,10 +123,26 @@ impl<'a> Line<'a> {
             start_pos: self.start_pos.plus_text(self.text),
             text: "",
             levels: 0,
+            hidden: false,
         }
     }
 }
 
+#[test]
+fn hidden_line_test() {
+    let line = Line::new(Pos::START, "# >> setup();\n", Marker::Comment);
+    assert_eq!(line.levels, 2);
+    let (_, line) = line.strip_one_marker();
+    assert!(!line.hidden);
+    let (marker, line) = line.strip_one_marker();
+    assert_eq!(marker.span, Span::new(Pos::new(2), Pos::new(4)));
+    assert!(line.hidden);
+    assert_eq!(line.text, " setup();\n");
+    let line = Line::new(Pos::START, "# > > x\n", Marker::Comment);
+    assert_eq!(line.levels, 2);
+    assert!(!line.strip_one_marker().1.strip_one_marker().1.hidden);
+}
+
 fn split_lines(source: &str) -> Result<(Vec<Line<'_>>, Marker)> {
     let first_marker = detect_first_marker_type(source)?;
     let mut lines = Vec::new();
@@ -190,6 +204,7 @@ pub(crate) fn parse(source: &str) -> Result<ast::Program> {
     match first_marker {
         Marker::Comment => {
             let code = parse_code(source, &lines)?;
+            check_no_yield(&code)?;
             Ok(ast::Program {
                 code,
                 code_markers: Vec::new(),
@@ -199,33 +214,96 @@ pub(crate) fn parse(source: &str) -> Result<ast::Program> {
             let comment = parse_comment(source, &lines, Vec::new())?.elements;
             let mut stmts = Vec::new();
             let mut code_markers = Vec::new();
+            let mut text = Vec::new();
             for element in comment {
                 match element {
-                    e @ ast::CommentElem::Text(_) => {
-                        match stmts.last_mut() {
-                            Some(ast::Stmt::Comment(c)) => c.elements.push(e),
-                            Some(_) | None => {
-                                stmts.push(ast::Stmt::Comment(ast::Comment {
-                                    markers: Vec::new(),
-                                    elements: vec![e],
-                                }));
-                            }
+                    e @ ast::CommentElem::Text { .. } => text.push(e),
+                    ast::CommentElem::Code { markers, attributes, code, hidden, expected_output } => {
+                        if let Some(attr) = attributes.first() {
+                            return Err(Error {
+                                message: "attributes are only allowed on snippets inside comments".to_owned(),
+                                span: attr.span,
+                            });
+                        }
+                        if let Some(marker) = hidden.first() {
+                            return Err(Error {
+                                message: "hidden lines are only allowed in snippets inside comments".to_owned(),
+                                span: marker.span,
+                            });
+                        }
+                        if !text.is_empty() {
+                            stmts.push(Rc::new(ast::Stmt::Comment(ast::Comment {
+                                markers: Vec::new(),
+                                elements: std::mem::take(&mut text),
+                                target: Default::default(),
+                            })));
                         }
-                    }
-                    ast::CommentElem::Code { markers, code } => {
                         code_markers.extend(markers);
-                        stmts.extend(code.consume_stmts());
+                        for stmt in code.stmts.iter() {
+                            push_stmt(source, &mut stmts, stmt.clone());
+                        }
+                        // program code doesn't have expected output, these
+                        // lines are just text
+                        text.extend(expected_output.into_iter().map(|text| {
+                            let line = Line {
+                                start_pos: text.span.start,
+                                text: &source[text.span.source_range()],
+                                levels: 0,
+                                hidden: false,
+                            };
+                            ast::CommentElem::Text { text, links: find_links(&line) }
+                        }));
                     }
                 }
             }
+            if !text.is_empty() {
+                stmts.push(Rc::new(ast::Stmt::Comment(ast::Comment {
+                    markers: Vec::new(),
+                    elements: text,
+                    target: Default::default(),
+                })));
+            }
             Ok(ast::Program {
-                code: ast::NakedBlock { stmts: stmts.into_iter().map(Rc::new).collect() },
+                code: ast::NakedBlock { stmts: stmts.into() },
                 code_markers,
             })
         }
     }
 }
 
+// Adds statement to a block. If it is a definition directly preceded by a
+// comment (with no blank lines in between), the comment is attached to it
+// as its documentation.
+fn push_stmt(src: &str, stmts: &mut Vec<Rc<ast::Stmt>>, stmt: Rc<ast::Stmt>) {
+    let comment = match stmts.last() {
+        Some(prev) if matches!(**prev, ast::Stmt::Comment(_)) => prev.clone(),
+        _ => {
+            stmts.push(stmt);
+            return;
+        }
+    };
+    let comment_end = src[..comment.span().end.source_pos()].trim_end().len();
+    let stmt_start = stmt.span().start.source_pos();
+    let newlines = src[comment_end..stmt_start].matches('\n').count();
+    let only_markers = src[comment_end..stmt_start]
+        .chars()
+        .all(|c| c.is_whitespace() || c == '#' || c == '>');
+    if newlines == 1 && only_markers {
+        let documented = match &*stmt {
+            ast::Stmt::Let { doc, .. } |
+            ast::Stmt::StructDef { doc, .. } => doc.set(comment.clone()).is_ok(),
+            ast::Stmt::FnDef(def) => def.doc.set(comment.clone()).is_ok(),
+            _ => false,
+        };
+        if documented {
+            if let ast::Stmt::Comment(c) = &*comment {
+                *c.target.borrow_mut() = Rc::downgrade(&stmt);
+            }
+        }
+    }
+    stmts.push(stmt);
+}
+
 fn detect_first_marker_type(source: &str) -> Result<Marker> {
     let mut first_marker = None;
     let mut pos = Pos::START;
@@ -251,45 +329,368 @@ fn detect_first_marker_type(source: &str) -> Result<Marker> {
     Ok(first_marker.map(|(kind, _)| kind).unwrap_or(Marker::Comment))
 }
 
+// Spans of all markers of the program, sorted by their position. Comments
+// and snippets record their own markers, lines of literate programs record
+// theirs in `code_markers`.
+pub(crate) fn markers(program: &ast::Program) -> Vec<Span> {
+    let mut markers = program.code_markers.iter().map(|t| t.span).collect::<Vec<_>>();
+    visit_comments(&program.code, &mut |comment| {
+        markers.extend(comment.markers.iter().map(|t| t.span));
+        for elem in &comment.elements {
+            if let ast::CommentElem::Code { markers: code_markers, .. } = elem {
+                markers.extend(code_markers.iter().map(|t| t.span));
+            }
+        }
+    });
+    markers.sort_by_key(|span| span.start);
+    markers
+}
+
+// Calls `f` for every comment of the block, including comments nested in
+// other statements and in snippets of other comments.
+fn visit_comments(block: &ast::NakedBlock, f: &mut dyn FnMut(&ast::Comment)) {
+    fn visit_tail(tail: &ast::IfTail, f: &mut dyn FnMut(&ast::Comment)) {
+        match tail {
+            ast::IfTail::None => {}
+            ast::IfTail::Else { body, .. } => visit_comments(&body.contents, f),
+            ast::IfTail::ElseIf { body, tail, .. } => {
+                visit_comments(&body.contents, f);
+                visit_tail(tail, f);
+            }
+        }
+    }
+    for stmt in block.stmts.iter() {
+        match &**stmt {
+            ast::Stmt::While { body, .. } |
+            ast::Stmt::For { body, .. } |
+            ast::Stmt::Block(body) => visit_comments(&body.contents, f),
+            ast::Stmt::If { body, tail, .. } => {
+                visit_comments(&body.contents, f);
+                visit_tail(tail, f);
+            }
+            ast::Stmt::FnDef(def) => visit_comments(&def.body.contents, f),
+            ast::Stmt::StructDef { fns, .. } => {
+                for def in fns {
+                    if let ast::Stmt::FnDef(def) = &**def {
+                        visit_comments(&def.body.contents, f);
+                    }
+                }
+            }
+            ast::Stmt::Comment(comment) => {
+                f(comment);
+                for elem in &comment.elements {
+                    if let ast::CommentElem::Code { code, .. } = elem {
+                        visit_comments(code, f);
+                    }
+                }
+            }
+            ast::Stmt::Expr { .. } |
+            ast::Stmt::Return { .. } |
+            ast::Stmt::Yield { .. } |
+            ast::Stmt::Let { .. } => {}
+        }
+    }
+}
+
+// End of the line containing `pos`, not including the line break.
+fn line_end(source: &str, pos: usize) -> usize {
+    let end = source[pos..].find('\n').map(|idx| pos + idx).unwrap_or(source.len());
+    if source[..end].ends_with('\r') { end - 1 } else { end }
+}
+
+// Text of every line starting at one of `starts` up to the end of the line,
+// with common indentation and surrounding blank lines removed.
+pub(crate) fn lines_content(source: &str, starts: impl Iterator<Item = usize>) -> String {
+    dedent(starts.map(|start| &source[start..line_end(source, start)]).collect())
+}
+
+fn dedent(mut lines: Vec<&str>) -> String {
+    while lines.last().map(|l| l.trim().is_empty()).unwrap_or(false) {
+        lines.pop();
+    }
+    let leading_blank = lines.iter().take_while(|l| l.trim().is_empty()).count();
+    lines.drain(..leading_blank);
+    let indent = lines
+        .iter()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| &l[..(l.len() - l.trim_start().len())])
+        .reduce(|a, b| {
+            let common = a.chars().zip(b.chars()).take_while(|(x, y)| x == y).map(|(x, _)| x.len_utf8()).sum();
+            &a[..common]
+        })
+        .unwrap_or("");
+    lines
+        .iter()
+        .map(|l| l.strip_prefix(indent).unwrap_or_else(|| l.trim_start()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Text of the comment without its markers, with common indentation and
+// surrounding blank lines removed.
+pub(crate) fn comment_content(source: &str, comment: &ast::Comment) -> String {
+    if comment.markers.is_empty() {
+        // text of a literate program, it has no markers
+        let starts = comment.elements.iter().filter_map(|elem| match elem {
+            ast::CommentElem::Text { text, .. } => Some(text.span.start.source_pos()),
+            ast::CommentElem::Code { .. } => None,
+        });
+        lines_content(source, starts)
+    } else {
+        lines_content(source, comment.markers.iter().map(|m| m.span.end.source_pos()))
+    }
+}
+
+// Returns source text of the code in `span` with `edits` applied to it. The
+// markers that place the code inside comments or snippets are removed from
+// every line, as well as common indentation and surrounding blank lines.
+// Edits must be sorted and can't start inside of the removed markers.
+pub(crate) fn code_content(source: &crate::Source, span: Span, edits: &[(Range<usize>, String)]) -> String {
+    let text = &source.text;
+    let start = span.start.source_pos();
+    let end = span.end.source_pos();
+    let first_line = text[..start].rfind('\n').map(|idx| idx + 1).unwrap_or(0);
+    // code is as deep as the markers preceding it on its first line
+    let depth = line_markers(source, first_line).take_while(|m| m.end.source_pos() <= start).count();
+    let mut lines = Vec::new();
+    let mut line = first_line;
+    while line < end {
+        let code_start = line_code_start(source, line, depth);
+        lines.push(std::cmp::max(code_start, start)..std::cmp::min(line_end(text, line), end));
+        line = text[line..].find('\n').map(|idx| line + idx + 1).unwrap_or(text.len());
+    }
+    // other code preceding the span on the first line is dropped, only
+    // indentation of the line is kept
+    let prefix = &text[line_code_start(source, first_line, depth)..start];
+    let mut content = prefix[..(prefix.len() - prefix.trim_start().len())].to_owned();
+    let mut edits = edits.iter().peekable();
+    let mut pos = start;
+    for (idx, line) in lines.iter().enumerate() {
+        if idx > 0 {
+            if pos > line.end {
+                // the whole line was replaced by an edit
+                continue;
+            }
+            if pos <= line.start {
+                content.push('\n');
+                pos = line.start;
+            }
+        }
+        while let Some((range, replacement)) = edits.next_if(|(range, _)| range.start <= line.end) {
+            content.push_str(&text[pos..range.start]);
+            content.push_str(replacement);
+            pos = range.end;
+        }
+        if pos <= line.end {
+            content.push_str(&text[pos..line.end]);
+            pos = line.end;
+        }
+    }
+    dedent(content.split('\n').collect())
+}
+
+// Start of the line containing `pos`, after the markers preceding `pos`.
+pub(crate) fn code_line_start(source: &crate::Source, pos: usize) -> usize {
+    let line = source.text[..pos].rfind('\n').map(|idx| idx + 1).unwrap_or(0);
+    match line_markers(source, line).take_while(|m| m.end.source_pos() <= pos).last() {
+        Some(marker) => marker.end.source_pos(),
+        None => line,
+    }
+}
+
+// Markers at the start of the line starting at `line_start`.
+fn line_markers(source: &crate::Source, line_start: usize) -> impl Iterator<Item = &Span> {
+    let end = line_end(&source.text, line_start);
+    let first = source.markers.partition_point(|m| m.start.source_pos() < line_start);
+    source.markers[first..].iter().take_while(move |m| m.start.source_pos() < end)
+}
+
+// Start of the code following the first `depth` markers of the line starting
+// at `line_start`.
+fn line_code_start(source: &crate::Source, line_start: usize, depth: usize) -> usize {
+    match depth.checked_sub(1).and_then(|idx| line_markers(source, line_start).nth(idx)) {
+        Some(marker) => marker.end.source_pos(),
+        None => line_start,
+    }
+}
+
 fn parse_comment(source: &str, mut lines: &[Line<'_>], markers: Vec<ast::Token>) -> Result<ast::Comment> {
     let mut elements = Vec::new();
     while lines.len() > 0 {
         let first_line = &lines[0];
         if first_line.levels == 0 {
-            let span = Span::new(
-                first_line.start_pos,
-                first_line.start_pos.plus_text(first_line.text),
-            );
-            elements.push(ast::CommentElem::Text(ast::Token {
-                span,
-                kind: TokenKind::CommentText,
-            }));
+            elements.push(ast::CommentElem::Text {
+                text: text_token(first_line),
+                links: find_links(first_line),
+            });
             lines = &lines[1..];
         } else {
             let mut code_lines = Vec::new();
             let mut code_markers = Vec::new();
+            let mut hidden = Vec::new();
             while lines.get(0).map(|l| l.levels > 0).unwrap_or(false) {
                 let (marker, line) = lines[0].strip_one_marker();
+                if line.hidden {
+                    hidden.push(marker);
+                }
                 code_lines.push(line);
                 code_markers.push(marker);
                 lines = &lines[1..];
             }
+            let attributes = match code_lines.first() {
+                Some(line) => parse_attributes(*line)?,
+                None => Vec::new(),
+            };
+            if !attributes.is_empty() {
+                code_lines.remove(0);
+            }
             let code = parse_code(source, &code_lines)?;
+            // both snippets and code of literate programs
+            check_no_yield(&code)?;
+            let mut expected_output = Vec::new();
+            while let Some(line) = lines.first().filter(|l| is_expected_output(l)) {
+                expected_output.push(text_token(line));
+                lines = &lines[1..];
+            }
             elements.push(ast::CommentElem::Code {
                 markers: code_markers,
+                attributes,
                 code,
+                hidden,
+                expected_output,
             });
         }
     }
     Ok(ast::Comment {
         markers,
         elements,
+        target: Default::default(),
     })
 }
 
+fn text_token(line: &Line<'_>) -> ast::Token {
+    ast::Token {
+        span: Span::new(line.start_pos, line.start_pos.plus_text(line.text)),
+        kind: TokenKind::CommentText,
+    }
+}
+
+// Finds `[name]` links in a line of text. Markdown links like `[text](url)`
+// aren't treated as links to definitions.
+fn find_links(line: &Line<'_>) -> Vec<ast::Link> {
+    let mut links = Vec::new();
+    let mut rest = line.text;
+    while let Some(open) = rest.find('[') {
+        let after = &rest[(open + 1)..];
+        let close = match after.find(']') {
+            Some(close) => close,
+            None => break,
+        };
+        let name = &after[..close];
+        let is_name = matches!(crate::lexer::next_token(name), Some((TokenKind::Name, len)) if len == name.len());
+        if is_name && !after[(close + 1)..].starts_with('(') {
+            let start = line.start_pos.plus_text(&line.text[..(line.text.len() - after.len())]);
+            links.push(ast::Link {
+                name: ast::Token {
+                    kind: TokenKind::Name,
+                    span: Span::new(start, start.plus_text(name)),
+                },
+            });
+        }
+        rest = after;
+    }
+    links
+}
+
+// Top-level definitions of the program by name, these are what `[name]`
+// links in comments point to.
+pub(crate) fn definitions(src: &str, program: &ast::NakedBlock) -> HashMap<String, Rc<ast::Stmt>> {
+    let mut defs = HashMap::new();
+    for stmt in program.stmts.iter() {
+        let name = match &**stmt {
+            ast::Stmt::FnDef(def) => def.name,
+            ast::Stmt::Let { name, .. } |
+            ast::Stmt::StructDef { name, .. } => *name,
+            _ => continue,
+        };
+        defs.insert(src[name.span.source_range()].to_owned(), stmt.clone());
+    }
+    defs
+}
+
+// Returns errors for links in comments of the program that don't point to
+// any top-level definition.
+pub(crate) fn broken_links(source: &crate::Source, program: &ast::NakedBlock) -> Vec<Error> {
+    let mut errors = Vec::new();
+    visit_comments(program, &mut |comment| {
+        for elem in &comment.elements {
+            if let ast::CommentElem::Text { links, .. } = elem {
+                for link in links {
+                    if source.link_target(link).is_none() {
+                        errors.push(Error {
+                            message: format!(
+                                "broken link: `{}` is not defined at the top level",
+                                &source.text[link.name.span.source_range()],
+                            ),
+                            span: link.name.span,
+                        });
+                    }
+                }
+            }
+        }
+    });
+    errors
+}
+
+// Text lines like `=> 42` following a snippet are what it should print.
+fn is_expected_output(line: &Line<'_>) -> bool {
+    line.levels == 0 && line.text.trim_start().starts_with("=>")
+}
+
+// Returns what a snippet should print according to its `=>` lines, each of
+// them is one line of output.
+pub(crate) fn expected_output(source: &str, lines: &[Span]) -> String {
+    let mut output = String::new();
+    for span in lines {
+        let line = source[span.source_range()].trim_start();
+        let line = line.strip_prefix("=>").unwrap_or(line);
+        let line = line.strip_prefix(' ').unwrap_or(line);
+        output.push_str(line.trim_end_matches(&['\r', '\n'][..]));
+        output.push('\n');
+    }
+    output
+}
+
+// Parses the line if it consists only of snippet attributes, otherwise it is
+// left to be parsed as code.
+fn parse_attributes(mut line: Line<'_>) -> Result<Vec<ast::Attribute>> {
+    if line.levels != 0 {
+        return Ok(Vec::new());
+    }
+    let mut attributes = Vec::new();
+    while let Some((kind, len)) = crate::lexer::next_token(line.text) {
+        let text = &line.text[..len];
+        let span = Span::new(line.start_pos, line.start_pos.plus_text(text));
+        match kind {
+            TokenKind::Attribute => {
+                let kind = ast::SnippetAttr::from_name(&text[1..]).ok_or_else(|| Error {
+                    message: format!("unknown snippet attribute `{}`", text),
+                    span,
+                })?;
+                attributes.push(ast::Attribute { kind, span });
+            }
+            TokenKind::Space | TokenKind::Newline => {}
+            _ => return Ok(Vec::new()),
+        }
+        line.text = &line.text[len..];
+        line.start_pos = span.end;
+    }
+    Ok(attributes)
+}
+
 fn parse_code(src: &str, lines: &[Line<'_>]) -> Result<ast::NakedBlock> {
     if lines.is_empty() {
-        return Ok(ast::NakedBlock { stmts: Vec::new() })
+        return Ok(ast::NakedBlock { stmts: Rc::new([]) })
     }
     let mut parser = Parser::new(src, lines);
     let block = parser.parse_naked_block()?;
@@ -511,9 +912,10 @@ impl<'a, 'src> Parser<'a, 'src> {
     fn parse_naked_block(&mut self) -> Result<ast::NakedBlock> {
         let mut stmts = Vec::new();
         while self.peek().map(|t| can_start_stmt(t)).unwrap_or(false) {
-            stmts.push(Rc::new(self.parse_stmt()?));
+            let stmt = Rc::new(self.parse_stmt()?);
+            push_stmt(self.src, &mut stmts, stmt);
         }
-        Ok(ast::NakedBlock { stmts })
+        Ok(ast::NakedBlock { stmts: stmts.into() })
     }
 
     fn parse_stmt(&mut self) -> Result<ast::Stmt> {
@@ -554,10 +956,11 @@ impl<'a, 'src> Parser<'a, 'src> {
                         left_curly,
                         fns,
                         right_curly,
+                        doc: Default::default(),
                     });
                 }
                 let fn_tok = self.expect(TokenKind::Fn)?;
-                fns.push(self.parse_fn_def(fn_tok)?);
+                fns.push(Rc::new(ast::Stmt::FnDef(Rc::new(self.parse_fn_def(fn_tok)?))));
             }
 
         } else if let Some(fn_tok) = self.check(TokenKind::Fn) {
@@ -570,6 +973,14 @@ impl<'a, 'src> Parser<'a, 'src> {
                 let semi = self.expect(TokenKind::Semicolon)?;
                 Ok(ast::Stmt::Return { ret, value: Some(value), semi })
             }
+        } else if let Some(yield_tok) = self.check(TokenKind::Yield) {
+            if let Some(semi) = self.check(TokenKind::Semicolon) {
+                Ok(ast::Stmt::Yield { yield_tok, value: None, semi })
+            } else {
+                let value = self.parse_expr(Prec::Min)?;
+                let semi = self.expect(TokenKind::Semicolon)?;
+                Ok(ast::Stmt::Yield { yield_tok, value: Some(value), semi })
+            }
         } else if self.peek() == Some(TokenKind::CommentMarker) {
             if self.current_line.levels == 0 {
                 return Err(Error {
@@ -603,7 +1014,7 @@ impl<'a, 'src> Parser<'a, 'src> {
             let eq = self.expect(TokenKind::Equals)?;
             let value = self.parse_expr(Prec::Min)?;
             let semi = self.expect(TokenKind::Semicolon)?;
-            Ok(ast::Stmt::Let { let_tok, name, eq, value, semi })
+            Ok(ast::Stmt::Let { let_tok, name, eq, value, semi, doc: Default::default() })
         } else {
             let expr = self.parse_expr(Prec::Min)?;
             let semi = self.expect(TokenKind::Semicolon)?;
@@ -647,6 +1058,7 @@ impl<'a, 'src> Parser<'a, 'src> {
         let params = self.parse_list(|p| p.expect(TokenKind::Name))?;
         let right_paren = self.expect(TokenKind::RightParen)?;
         let body = self.parse_block()?;
+        let is_generator = contains_yield(&body.contents);
         Ok(ast::FnDef {
             fn_tok,
             name,
@@ -654,10 +1066,12 @@ impl<'a, 'src> Parser<'a, 'src> {
             params,
             right_paren,
             body,
+            is_generator,
+            doc: Default::default(),
         })
     }
 
-    fn parse_expr(&mut self, min_prec: Prec) -> Result<ast::Expr> {
+    fn parse_expr(&mut self, min_prec: Prec) -> Result<Rc<ast::Expr>> {
         self.hint(ParseHint::Expr);
         let mut expr = self.parse_operand_expr()?;
         while let Some(kind) = self.peek() {
@@ -672,16 +1086,16 @@ impl<'a, 'src> Parser<'a, 'src> {
                                 expr = ast::Expr::AssignVar {
                                     name,
                                     eq: operator,
-                                    value: Box::new(rhs),
+                                    value: rhs,
                                 };
                             }
-                            ast::Expr::Field { obj, dot, field } => {
+                            ast::Expr::Field { obj, dot, field } if dot.kind == TokenKind::Dot => {
                                 expr = ast::Expr::AssignField {
                                     obj,
                                     dot,
                                     field,
                                     eq: operator,
-                                    value: Box::new(rhs),
+                                    value: rhs,
                                 };
                             }
                             _ => {
@@ -693,9 +1107,9 @@ impl<'a, 'src> Parser<'a, 'src> {
                         }
                     } else {
                         expr = ast::Expr::BinOp {
-                            lhs: Box::new(expr),
+                            lhs: Rc::new(expr),
                             operator,
-                            rhs: Box::new(rhs),
+                            rhs,
                         };
                     }
                 }
@@ -703,7 +1117,7 @@ impl<'a, 'src> Parser<'a, 'src> {
                 _ => break,
             }
         }
-        Ok(expr)
+        Ok(Rc::new(expr))
     }
 
     fn parse_operand_expr(&mut self) -> Result<ast::Expr> {
@@ -712,21 +1126,21 @@ impl<'a, 'src> Parser<'a, 'src> {
             let arg = self.parse_operand_expr()?;
             Ok(ast::Expr::PrefixOp {
                 operator,
-                arg: Box::new(arg),
+                arg: Rc::new(arg),
             })
         } else if let Some(operator) = self.check(TokenKind::Bang) {
             let arg = self.parse_operand_expr()?;
             Ok(ast::Expr::PrefixOp {
                 operator,
-                arg: Box::new(arg),
+                arg: Rc::new(arg),
             })
         } else {
             let mut expr = self.parse_atom_expr()?;
             loop {
-                if let Some(dot) = self.check(TokenKind::Dot) {
+                if let Some(dot) = self.check(TokenKind::Dot).or_else(|| self.check(TokenKind::QuestionDot)) {
                     let field = self.expect(TokenKind::Name)?;
                     expr = ast::Expr::Field {
-                        obj: Box::new(expr),
+                        obj: Rc::new(expr),
                         dot,
                         field,
                     };
@@ -734,7 +1148,7 @@ impl<'a, 'src> Parser<'a, 'src> {
                     let args = self.parse_list(|p| p.parse_expr(Prec::Min))?;
                     let right_paren = self.expect(TokenKind::RightParen)?;
                     expr = ast::Expr::Call {
-                        func: Box::new(expr),
+                        func: Rc::new(expr),
                         left_paren,
                         args,
                         right_paren,
@@ -788,12 +1202,35 @@ impl<'a, 'src> Parser<'a, 'src> {
                 &source[1..(source.len() - 1)],
             )?;
             Ok(ast::Expr::Str { tok, value })
+        } else if let Some(left_bracket) = self.check(TokenKind::LeftBracket) {
+            let item = self.parse_expr(Prec::Min)?;
+            let for_tok = self.expect(TokenKind::For)?;
+            let name = self.expect(TokenKind::Name)?;
+            let in_tok = self.expect(TokenKind::In)?;
+            let iterable = self.parse_expr(Prec::Min)?;
+            let if_tok = self.check(TokenKind::If);
+            let filter = match if_tok {
+                Some(_) => Some(self.parse_expr(Prec::Min)?),
+                None => None,
+            };
+            let right_bracket = self.expect(TokenKind::RightBracket)?;
+            Ok(ast::Expr::ListComp(Box::new(ast::ListComp {
+                left_bracket,
+                item,
+                for_tok,
+                name,
+                in_tok,
+                iterable,
+                if_tok,
+                filter,
+                right_bracket,
+            })))
         } else if let Some(left_paren) = self.check(TokenKind::LeftParen) {
             let inner = self.parse_expr(Prec::Min)?;
             let right_paren = self.expect(TokenKind::RightParen)?;
             Ok(ast::Expr::Paren {
                 left_paren,
-                inner: Box::new(inner),
+                inner,
                 right_paren,
             })
         } else {
@@ -808,21 +1245,48 @@ impl<'a, 'src> Parser<'a, 'src> {
 
 fn parse_string_value(mut pos: Pos, text: &str) -> Result<String> {
     let mut result = String::new();
-    let mut chars = text.chars();
+    let mut chars = text.chars().peekable();
     while let Some(c) = chars.next() {
         if c == '\\' {
             let start_pos = pos;
             pos = pos.plus_char(c);
             let next = chars.next();
-            pos = pos.plus_char(c);
-            let escape_span = Span::new(start_pos, pos);
+            if let Some(next) = next {
+                pos = pos.plus_char(next);
+            }
             let escaped = match next {
                 Some('n') => '\n',
                 Some('r') => '\r',
+                Some('t') => '\t',
+                Some('0') => '\0',
                 Some('\\') => '\\',
                 Some('"') => '"',
+                Some('u') if chars.peek() == Some(&'{') => {
+                    pos = pos.plus_char(chars.next().unwrap());
+                    let mut code = String::new();
+                    let mut closed = false;
+                    for c in &mut chars {
+                        pos = pos.plus_char(c);
+                        if c == '}' {
+                            closed = true;
+                            break;
+                        }
+                        code.push(c);
+                    }
+                    let escaped = u32::from_str_radix(&code, 16)
+                        .ok()
+                        .filter(|_| closed && code.len() <= 6 && code.chars().all(|c| c.is_ascii_hexdigit()))
+                        .and_then(std::char::from_u32);
+                    match escaped {
+                        Some(c) => c,
+                        None => return Err(Error {
+                            span: Span::new(start_pos, pos),
+                            message: "invalid unicode escape".to_owned(),
+                        }),
+                    }
+                }
                 _ => return Err(Error {
-                    span: escape_span,
+                    span: Span::new(start_pos, pos),
                     message: "invalid escape sequence".to_owned(),
                 }),
             };
@@ -835,10 +1299,78 @@ fn parse_string_value(mut pos: Pos, text: &str) -> Result<String> {
     Ok(result)
 }
 
+#[test]
+fn parse_string_value_test() {
+    fn check(text: &str, expected: Option<&str>) {
+        let actual = parse_string_value(Pos::START, text).ok();
+        assert_eq!(expected, actual.as_deref());
+    }
+    check("plain", Some("plain"));
+    check("a\\nb", Some("a\nb"));
+    check("\\t\\0\\\\\\\"", Some("\t\0\\\""));
+    check("\\u{48}\\u{1F600}", Some("H\u{1F600}"));
+    check("ąžuolas", Some("ąžuolas"));
+    check("\\q", None);
+    check("\\u{}", None);
+    check("\\u{110000}", None);
+    check("\\u{D800}", None);
+    check("\\u{1234567}", None);
+    check("\\u{48", None);
+    check("\\u48", None);
+    check("\\u{{48}", None);
+}
+
+// Checks if a block yields, not counting nested function definitions.
+fn contains_yield(block: &ast::NakedBlock) -> bool {
+    find_yield(block).is_some()
+}
+
+// Returns the first `yield` of the block that belongs to it, and not to
+// functions defined inside of it.
+fn find_yield(block: &ast::NakedBlock) -> Option<Span> {
+    fn tail_yield(tail: &ast::IfTail) -> Option<Span> {
+        match tail {
+            ast::IfTail::None => None,
+            ast::IfTail::Else { body, .. } => find_yield(&body.contents),
+            ast::IfTail::ElseIf { body, tail, .. } => {
+                find_yield(&body.contents).or_else(|| tail_yield(tail))
+            }
+        }
+    }
+    block.stmts.iter().find_map(|stmt| match &**stmt {
+        ast::Stmt::Yield { yield_tok, .. } => Some(yield_tok.span),
+        ast::Stmt::While { body, .. } |
+        ast::Stmt::For { body, .. } |
+        ast::Stmt::Block(body) => find_yield(&body.contents),
+        ast::Stmt::If { body, tail, .. } => {
+            find_yield(&body.contents).or_else(|| tail_yield(tail))
+        }
+        ast::Stmt::Expr { .. } |
+        ast::Stmt::Return { .. } |
+        ast::Stmt::Let { .. } |
+        ast::Stmt::Comment(_) |
+        ast::Stmt::FnDef(_) |
+        ast::Stmt::StructDef { .. } => None,
+    })
+}
+
+// Code outside of function bodies can't `yield`, there is no generator to
+// suspend.
+fn check_no_yield(block: &ast::NakedBlock) -> Result<()> {
+    match find_yield(block) {
+        Some(span) => Err(Error {
+            message: "cannot use yield outside of a function".to_owned(),
+            span,
+        }),
+        None => Ok(()),
+    }
+}
+
 #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 enum Prec {
     Min,
     Assign,
+    Coalesce,
     Or,
     And,
     Equals,
@@ -851,6 +1383,7 @@ enum Prec {
 fn binop_prec(token: TokenKind) -> Option<(Prec, Prec)> {
     match token {
         TokenKind::Equals => Some((Prec::Assign, Prec::Assign)),
+        TokenKind::QuestionQuestion => Some((Prec::Coalesce, Prec::Or)),
         TokenKind::Or => Some((Prec::Or, Prec::And)),
         TokenKind::And => Some((Prec::And, Prec::Equals)),
         TokenKind::EqEq |
@@ -875,6 +1408,7 @@ fn can_start_expr(token: TokenKind) -> bool {
         TokenKind::Nil |
         TokenKind::SelfKw |
         TokenKind::LeftParen |
+        TokenKind::LeftBracket |
         TokenKind::Name |
         TokenKind::Number => true,
         _ => false,
@@ -889,6 +1423,7 @@ fn can_start_stmt(token: TokenKind) -> bool {
         TokenKind::If |
         TokenKind::For |
         TokenKind::Return |
+        TokenKind::Yield |
         TokenKind::Struct |
         TokenKind::True |
         TokenKind::False |
@@ -896,6 +1431,7 @@ fn can_start_stmt(token: TokenKind) -> bool {
         TokenKind::Nil |
         TokenKind::SelfKw |
         TokenKind::LeftParen |
+        TokenKind::LeftBracket |
         TokenKind::LeftCurly |
         TokenKind::Name |
         TokenKind::Number |
@@ -918,7 +1454,8 @@ fn is_operator(token: TokenKind) -> bool {
         TokenKind::Greater |
         TokenKind::GreaterEq |
         TokenKind::EqEq |
-        TokenKind::NotEq => true,
+        TokenKind::NotEq |
+        TokenKind::QuestionQuestion => true,
         _ => false,
     }
 }